@@ -1,25 +1,94 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use eframe::{
     App,
     egui::{
-        self, Button, CentralPanel, ColorImage, Image, ProgressBar, SidePanel, TextureHandle,
-        TextureOptions, Ui, Widget, load::SizedTexture, vec2,
+        self, Button, CentralPanel, ColorImage, Key, KeyboardShortcut, Modifiers, ProgressBar,
+        ScrollArea, SidePanel, TextureFilter, TextureHandle, TextureOptions, Ui, Widget, Window,
+        load::SizedTexture, vec2,
     },
 };
 use image::{DynamicImage, EncodableLayout};
 
 use crate::{
-    commands::CommandQueue,
+    batch::{self, BatchFailure},
+    commands::{CancelToken, CommandQueue, History},
+    viewer::ImageViewer,
     worker::{ImageWorker, WorkerResult},
 };
 
 pub struct Application {
     worker: ImageWorker,
     path: Option<PathBuf>,
-    base_img: Option<DynamicImage>,
+    base_img: Option<Arc<DynamicImage>>,
+    /// Bumped whenever a new image is loaded, so renders can tell base images apart
+    base_generation: u64,
+    base_texture: Option<TextureHandle>,
     img: ImageLoadState,
+    viewer: ImageViewer,
     queue: CommandQueue,
+    batch: BatchState,
+    live_preview: LivePreview,
+    history: History,
+    history_open: bool,
+    keep_steps: bool,
+    steps: Vec<Option<StepResult>>,
+    step_hashes: Vec<u64>,
+}
+
+/// Thumbnail of a single queue step, kept when step previews are enabled.
+/// The full result stays in the worker until the thumbnail is clicked.
+struct StepResult {
+    /// Hash of the queue prefix that produced this result
+    hash: u64,
+    thumbnail: TextureHandle,
+}
+
+/// Largest displayed width or height of step thumbnails.
+const THUMBNAIL_DISPLAY_SIZE: f32 = 32.;
+
+const UNDO_SHORTCUT: KeyboardShortcut = KeyboardShortcut::new(Modifiers::COMMAND, Key::Z);
+const REDO_SHORTCUT: KeyboardShortcut =
+    KeyboardShortcut::new(Modifiers::COMMAND.plus(Modifiers::SHIFT), Key::Z);
+
+/// How long the queue must stay unchanged before a live preview is rendered.
+const PREVIEW_DEBOUNCE: Duration = Duration::from_millis(300);
+
+#[derive(Default)]
+struct LivePreview {
+    enabled: bool,
+    queue_hash: Option<u64>,
+    changed_at: Option<Instant>,
+    pending: Option<CancelToken>,
+    error: Option<String>,
+}
+
+struct BatchState {
+    open: bool,
+    input_dir: Option<PathBuf>,
+    pattern: String,
+    output_dir: Option<PathBuf>,
+    template: String,
+    progress: Option<BatchProgress>,
+}
+
+struct BatchProgress {
+    done: usize,
+    total: usize,
+    failures: Vec<BatchFailure>,
+    cancel: CancelToken,
+    cancelled: bool,
+}
+
+impl BatchProgress {
+    fn running(&self) -> bool {
+        self.done < self.total && !self.cancelled
+    }
 }
 
 const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -52,14 +121,60 @@ fn deserialize_from_file(queue: &mut CommandQueue) -> BoxResult<()> {
     Ok(())
 }
 
+fn display_path(path: &Option<PathBuf>) -> String {
+    path.as_ref()
+        .map(|d| d.display().to_string())
+        .unwrap_or("None".into())
+}
+
+/// Textures are sampled with nearest neighbour filtering when zoomed in, to inspect single pixels.
+const PIXEL_TEXTURE_OPTIONS: TextureOptions = TextureOptions {
+    magnification: TextureFilter::Nearest,
+    ..TextureOptions::LINEAR
+};
+
+fn load_texture(ctx: &egui::Context, img: &DynamicImage, options: TextureOptions) -> TextureHandle {
+    let img_gui = match img {
+        DynamicImage::ImageRgb8(img) => ColorImage::from_rgb(
+            [img.width() as usize, img.height() as usize],
+            img.as_bytes(),
+        ),
+        other => {
+            let img = other.to_rgba8();
+            ColorImage::from_rgba_unmultiplied(
+                [img.width() as usize, img.height() as usize],
+                img.as_bytes(),
+            )
+        }
+    };
+    ctx.load_texture("preview", img_gui, options)
+}
+
 impl Application {
     pub fn new() -> Self {
         Self {
             worker: ImageWorker::new(),
             path: None,
             base_img: None,
+            base_generation: 0,
+            base_texture: None,
             img: ImageLoadState::None,
+            viewer: ImageViewer::new(),
             queue: CommandQueue::default(),
+            batch: BatchState {
+                open: false,
+                input_dir: None,
+                pattern: "*".into(),
+                output_dir: None,
+                template: batch::DEFAULT_TEMPLATE.into(),
+                progress: None,
+            },
+            live_preview: LivePreview::default(),
+            history: History::default(),
+            history_open: false,
+            keep_steps: false,
+            steps: vec![],
+            step_hashes: vec![],
         }
     }
 
@@ -67,58 +182,432 @@ impl Application {
         if let Some(res) = self.worker.try_recv() {
             match res {
                 WorkerResult::Finished(img) => {
+                    let handle = load_texture(ctx, &img, PIXEL_TEXTURE_OPTIONS);
                     if matches!(self.img, ImageLoadState::Loading) {
-                        self.base_img = Some(img.clone());
+                        self.viewer.reset_view();
+                        self.base_img = Some(Arc::new(img.clone()));
+                        self.base_generation += 1;
+                        self.base_texture = Some(handle.clone());
                     }
-                    let img_gui = match img.clone() {
-                        DynamicImage::ImageRgb8(img) => ColorImage::from_rgb(
-                            [img.width() as usize, img.height() as usize],
-                            img.as_bytes(),
-                        ),
-                        other => {
-                            let img = other.to_rgba8();
-                            ColorImage::from_rgba_unmultiplied(
-                                [img.width() as usize, img.height() as usize],
-                                img.as_bytes(),
-                            )
-                        }
-                    };
-                    let handle = ctx.load_texture("preview", img_gui, TextureOptions::default());
                     let tex = SizedTexture::from_handle(&handle);
-                    self.img = ImageLoadState::Loaded { handle, tex, img }
+                    self.img = ImageLoadState::Loaded {
+                        handle,
+                        tex,
+                        img,
+                        kind: RenderKind::Full,
+                    }
+                }
+                WorkerResult::PreviewFinished { img, proxy_size } => {
+                    // Previews requested before a render or image load are stale
+                    if self.live_preview.pending.take().is_some()
+                        && matches!(self.img, ImageLoadState::Loaded { .. })
+                        && let Some(base_img) = &self.base_img
+                    {
+                        // The proxy is upscaled, so keep it smooth
+                        let handle = load_texture(ctx, &img, TextureOptions::LINEAR);
+                        // Scale the preview up as the proxy was scaled down, as the queue may
+                        // have changed its size
+                        let (proxy_width, proxy_height) = proxy_size;
+                        let size = vec2(
+                            img.width() as f32 * base_img.width() as f32 / proxy_width as f32,
+                            img.height() as f32 * base_img.height() as f32 / proxy_height as f32,
+                        );
+                        let tex = SizedTexture::new(handle.id(), size);
+                        self.img = ImageLoadState::Loaded {
+                            handle,
+                            tex,
+                            img,
+                            kind: RenderKind::Preview,
+                        };
+                        self.live_preview.error = None;
+                    }
+                }
+                WorkerResult::PreviewError(e) => {
+                    if self.live_preview.pending.take().is_some() {
+                        self.live_preview.error = Some(e.to_string());
+                    }
+                }
+                WorkerResult::Step { step, thumbnail } => {
+                    let i = step - 1;
+                    if let Some(&hash) = self.step_hashes.get(i) {
+                        if self.steps.len() <= i {
+                            self.steps.resize_with(i + 1, || None);
+                        }
+                        let thumbnail = load_texture(ctx, &thumbnail, TextureOptions::LINEAR);
+                        self.steps[i] = Some(StepResult { hash, thumbnail });
+                    }
+                }
+                WorkerResult::StepImage { step, img } => {
+                    let loaded = matches!(self.img, ImageLoadState::Loaded { .. });
+                    match img {
+                        Some(img) if loaded => {
+                            let handle = load_texture(ctx, &img, PIXEL_TEXTURE_OPTIONS);
+                            let tex = SizedTexture::from_handle(&handle);
+                            self.img = ImageLoadState::Loaded {
+                                handle,
+                                tex,
+                                img,
+                                kind: RenderKind::Step(step),
+                            };
+                        }
+                        // A new render or image load started in the meantime
+                        Some(_) => {}
+                        None => {
+                            if let Some(step) = self.steps.get_mut(step) {
+                                *step = None;
+                            }
+                            rfd::MessageDialog::new()
+                                .set_title("Step unavailable")
+                                .set_level(rfd::MessageLevel::Warning)
+                                .set_description(
+                                    "The result of this step is no longer kept, render the queue again to see it",
+                                )
+                                .show();
+                        }
+                    }
                 }
                 WorkerResult::Progress(i) => {
                     if let ImageLoadState::Rendering { progress, .. } = &mut self.img {
                         *progress = i;
                     }
                 }
+                WorkerResult::Cancelled => {
+                    if let ImageLoadState::Rendering { previous, .. } =
+                        std::mem::replace(&mut self.img, ImageLoadState::None)
+                    {
+                        self.img = *previous;
+                    }
+                }
                 WorkerResult::Error(e) => {
+                    match std::mem::replace(&mut self.img, ImageLoadState::None) {
+                        ImageLoadState::Rendering { previous, .. } => self.img = *previous,
+                        _ => {
+                            self.path = None;
+                            self.base_img = None;
+                            self.base_texture = None;
+                        }
+                    }
                     rfd::MessageDialog::new()
                         .set_title("Image error")
                         .set_level(rfd::MessageLevel::Error)
-                        .set_description(format!("{e}"));
+                        .set_description(format!("{e}"))
+                        .show();
+                }
+                WorkerResult::BatchProgress {
+                    done,
+                    total,
+                    failure,
+                } => {
+                    if let Some(progress) = &mut self.batch.progress {
+                        progress.done = done;
+                        progress.total = total;
+                        progress.failures.extend(failure);
+                    }
+                }
+                WorkerResult::BatchCancelled => {
+                    if let Some(progress) = &mut self.batch.progress {
+                        progress.cancelled = true;
+                    }
                 }
             }
         }
     }
 
+    fn update_live_preview(&mut self, ctx: &egui::Context) {
+        let preview = &mut self.live_preview;
+        if !preview.enabled {
+            if let Some(cancel) = preview.pending.take() {
+                cancel.cancel();
+            }
+            preview.queue_hash = None;
+            preview.error = None;
+            return;
+        }
+        let hash = self.queue.content_hash();
+        if preview.queue_hash != Some(hash) {
+            preview.queue_hash = Some(hash);
+            preview.changed_at = Some(Instant::now());
+        }
+        if preview.pending.is_some() {
+            ctx.request_repaint();
+            return;
+        }
+        let (Some(changed_at), Some(base_img), ImageLoadState::Loaded { .. }) =
+            (preview.changed_at, &self.base_img, &self.img)
+        else {
+            return;
+        };
+        let elapsed = changed_at.elapsed();
+        if elapsed < PREVIEW_DEBOUNCE {
+            ctx.request_repaint_after(PREVIEW_DEBOUNCE - elapsed);
+            return;
+        }
+        preview.changed_at = None;
+        preview.pending = Some(self.worker.request_preview(
+            self.queue.clone(),
+            base_img.clone(),
+            self.base_generation,
+        ));
+        ctx.request_repaint();
+    }
+
+    /// Thumbnails of the step results that still match the current queue.
+    fn step_thumbnails(&self) -> Vec<Option<SizedTexture>> {
+        self.queue
+            .prefix_hashes()
+            .into_iter()
+            .zip(&self.steps)
+            .map(|(hash, step)| {
+                let step = step.as_ref().filter(|s| s.hash == hash)?;
+                let tex = SizedTexture::from_handle(&step.thumbnail);
+                let size = tex.size * (THUMBNAIL_DISPLAY_SIZE / tex.size.max_elem());
+                Some(SizedTexture::new(tex.id, size))
+            })
+            .collect()
+    }
+
+    fn show_step(&self, i: usize) {
+        if !matches!(self.img, ImageLoadState::Loaded { .. }) {
+            return;
+        }
+        if let Some(Some(step)) = self.steps.get(i) {
+            self.worker.request_step(i, step.hash);
+        }
+    }
+
+    fn cancel_live_preview(&mut self) {
+        if let Some(cancel) = self.live_preview.pending.take() {
+            cancel.cancel();
+        }
+    }
+
+    fn handle_history_shortcuts(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        // Redo is checked first since the undo shortcut also matches with shift held
+        if ctx.input_mut(|i| i.consume_shortcut(&REDO_SHORTCUT)) {
+            self.history.redo(&mut self.queue);
+        } else if ctx.input_mut(|i| i.consume_shortcut(&UNDO_SHORTCUT)) {
+            self.history.undo(&mut self.queue);
+        }
+    }
+
+    fn show_history_window(&mut self, ctx: &egui::Context) {
+        let mut undo = 0;
+        let mut redo = 0;
+        let history = &self.history;
+        Window::new("History")
+            .open(&mut self.history_open)
+            .default_width(200.)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let undo_text = ctx.format_shortcut(&UNDO_SHORTCUT);
+                    let redo_text = ctx.format_shortcut(&REDO_SHORTCUT);
+                    if ui
+                        .add_enabled(history.can_undo(), Button::new("Undo"))
+                        .on_hover_text(undo_text)
+                        .clicked()
+                    {
+                        undo = 1;
+                    }
+                    if ui
+                        .add_enabled(history.can_redo(), Button::new("Redo"))
+                        .on_hover_text(redo_text)
+                        .clicked()
+                    {
+                        redo = 1;
+                    }
+                });
+                ui.separator();
+                ScrollArea::vertical().max_height(300.).show(ui, |ui| {
+                    let undo_count = history.undo_labels().count();
+                    for (i, label) in history.undo_labels().enumerate() {
+                        if ui.selectable_label(false, label).clicked() {
+                            undo = undo_count - i;
+                        }
+                    }
+                    ui.label(egui::RichText::new("▶ Current").strong());
+                    for (i, label) in history.redo_labels().enumerate() {
+                        let text = egui::RichText::new(label).weak();
+                        if ui.selectable_label(false, text).clicked() {
+                            redo = i + 1;
+                        }
+                    }
+                });
+            });
+        for _ in 0..undo {
+            self.history.undo(&mut self.queue);
+        }
+        for _ in 0..redo {
+            self.history.redo(&mut self.queue);
+        }
+    }
+
+    fn start_batch(&mut self) {
+        let (Some(input_dir), Some(output_dir)) = (&self.batch.input_dir, &self.batch.output_dir)
+        else {
+            return;
+        };
+        let inputs = batch::collect_inputs(&[input_dir.join(&self.batch.pattern)])
+            .and_then(|inputs| fs::create_dir_all(output_dir).map(|_| inputs));
+        let inputs = match inputs {
+            Ok(inputs) if inputs.is_empty() => {
+                rfd::MessageDialog::new()
+                    .set_title("Batch error")
+                    .set_level(rfd::MessageLevel::Warning)
+                    .set_description("No images found in the selected folder")
+                    .show();
+                return;
+            }
+            Ok(inputs) => inputs,
+            Err(e) => {
+                rfd::MessageDialog::new()
+                    .set_title("Batch error")
+                    .set_level(rfd::MessageLevel::Error)
+                    .set_description(format!("Failed to prepare batch: {e}"))
+                    .show();
+                return;
+            }
+        };
+        let total = inputs.len();
+        let cancel = self.worker.request_batch(
+            self.queue.clone(),
+            inputs,
+            output_dir.clone(),
+            self.batch.template.clone(),
+        );
+        self.batch.progress = Some(BatchProgress {
+            done: 0,
+            total,
+            failures: vec![],
+            cancel,
+            cancelled: false,
+        });
+    }
+
+    fn show_batch_window(&mut self, ctx: &egui::Context) {
+        let running = self.batch.progress.as_ref().is_some_and(|p| p.running());
+        if running {
+            ctx.request_repaint();
+        }
+        let mut start_request = false;
+        let batch = &mut self.batch;
+        Window::new("Batch processing")
+            .open(&mut batch.open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.add_enabled_ui(!running, |ui| {
+                    ui.horizontal(|ui| {
+                        if ui.button("Input folder").clicked() {
+                            let dir = rfd::FileDialog::new()
+                                .set_title("Select input folder")
+                                .pick_folder();
+                            if dir.is_some() {
+                                batch.input_dir = dir;
+                            }
+                        }
+                        ui.label(display_path(&batch.input_dir));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("File filter");
+                        ui.text_edit_singleline(&mut batch.pattern);
+                    });
+                    ui.horizontal(|ui| {
+                        if ui.button("Output folder").clicked() {
+                            let dir = rfd::FileDialog::new()
+                                .set_title("Select output folder")
+                                .pick_folder();
+                            if dir.is_some() {
+                                batch.output_dir = dir;
+                            }
+                        }
+                        ui.label(display_path(&batch.output_dir));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.label("Output name");
+                        ui.text_edit_singleline(&mut batch.template);
+                    });
+                    ui.small("Use {name}, {ext} and {index} in the output name");
+                    let ready = batch.input_dir.is_some() && batch.output_dir.is_some();
+                    if ui.add_enabled(ready, Button::new("Start batch")).clicked() {
+                        start_request = true;
+                    }
+                });
+                if let Some(progress) = &batch.progress {
+                    ui.separator();
+                    let BatchProgress {
+                        done,
+                        total,
+                        failures,
+                        cancel,
+                        cancelled,
+                    } = progress;
+                    ProgressBar::new(*done as f32 / *total as f32)
+                        .animate(progress.running())
+                        .text(format!("{done} / {total} images processed"))
+                        .ui(ui);
+                    if progress.running() {
+                        if ui
+                            .add_enabled(!cancel.is_cancelled(), Button::new("Cancel"))
+                            .clicked()
+                        {
+                            cancel.cancel();
+                        }
+                    } else if *cancelled {
+                        ui.label(format!(
+                            "Cancelled: {} succeeded, {} failed, {} skipped",
+                            done - failures.len(),
+                            failures.len(),
+                            total - done
+                        ));
+                    } else {
+                        ui.label(format!(
+                            "Done: {} succeeded, {} failed",
+                            total - failures.len(),
+                            failures.len()
+                        ));
+                    }
+                    if !failures.is_empty() {
+                        ScrollArea::vertical().max_height(150.).show(ui, |ui| {
+                            for failure in failures {
+                                ui.label(format!("{}: {}", failure.path.display(), failure.error));
+                            }
+                        });
+                    }
+                }
+            });
+        if start_request {
+            self.start_batch();
+        }
+    }
+
     fn show_controls(&mut self, ui: &mut Ui) {
         ui.horizontal(|ui| {
-            if ui.button("Browse").clicked() {
+            // The result of a running render would be taken for the loaded image
+            let rendering = matches!(self.img, ImageLoadState::Rendering { .. });
+            if ui
+                .add_enabled(!rendering, Button::new("Browse"))
+                .on_disabled_hover_text("Wait for the render to finish or cancel it")
+                .clicked()
+            {
                 let file = rfd::FileDialog::new().set_title("Select image").pick_file();
                 if let Some(file) = file {
+                    self.cancel_live_preview();
+                    self.live_preview.queue_hash = None;
                     self.img = ImageLoadState::Loading;
+                    self.steps.clear();
                     self.worker.request_image_load(file.clone());
                     self.path = Some(file);
                 }
             }
-            ui.label(format!(
-                "Selected: {}",
-                self.path
-                    .as_ref()
-                    .map(|d| d.display().to_string())
-                    .unwrap_or("None".into())
-            ))
+            if ui.button("Batch").clicked() {
+                self.batch.open = true;
+            }
+            if ui.button("History").clicked() {
+                self.history_open = true;
+            }
+            ui.label(format!("Selected: {}", display_path(&self.path)))
         });
         ui.separator();
         if self.path.is_some() {
@@ -147,6 +636,14 @@ impl Application {
                         }
                     }
                 }
+                if ui
+                    .checkbox(&mut self.keep_steps, "Step previews")
+                    .on_hover_text("Keep the result of every step when rendering")
+                    .changed()
+                    && !self.keep_steps
+                {
+                    self.steps.clear();
+                }
             });
             ui.separator();
             let available_width = ui.available_width();
@@ -155,22 +652,41 @@ impl Application {
                 let spacing = ui.spacing();
                 available - spacing.item_spacing.y * 9. - spacing.interact_size.y
             };
-            ui.allocate_ui(vec2(available_width, available_height), |ui| {
-                self.queue.ui(ui);
-                ui.add_space(ui.available_height());
-            });
+            let thumbnails = self.step_thumbnails();
+            let clicked_step = ui
+                .allocate_ui(vec2(available_width, available_height), |ui| {
+                    let clicked_step = self.queue.ui(ui, &thumbnails);
+                    ui.add_space(ui.available_height());
+                    clicked_step
+                })
+                .inner;
+            if let Some(i) = clicked_step {
+                self.show_step(i);
+            }
             ui.separator();
-            let mut render_request = false;
+            let mut render_request = None;
             match &self.img {
-                ImageLoadState::None
-                | ImageLoadState::Loading
-                | ImageLoadState::Rendering { .. } => {
+                ImageLoadState::None | ImageLoadState::Loading => {
                     ui.horizontal(|ui| {
                         ui.add_enabled(false, Button::new("Render"));
                         ui.add_enabled(false, Button::new("Save current render"));
+                        ui.checkbox(&mut self.live_preview.enabled, "Live preview");
+                    });
+                }
+                ImageLoadState::Rendering { cancel, .. } => {
+                    ui.horizontal(|ui| {
+                        if ui
+                            .add_enabled(!cancel.is_cancelled(), Button::new("Cancel"))
+                            .clicked()
+                        {
+                            println!("Cancelling image render");
+                            cancel.cancel();
+                        }
+                        ui.add_enabled(false, Button::new("Save current render"));
+                        ui.checkbox(&mut self.live_preview.enabled, "Live preview");
                     });
                 }
-                ImageLoadState::Loaded { img, .. } => {
+                ImageLoadState::Loaded { img, kind, .. } => {
                     let base_img = self
                         .base_img
                         .as_ref()
@@ -178,40 +694,56 @@ impl Application {
                     ui.horizontal(|ui| {
                         if ui.button("Render").clicked() {
                             println!("Requesting image render with {} filters", self.queue.len());
-                            self.worker
-                                .request_render(self.queue.clone(), base_img.clone());
-                            render_request = true;
+                            render_request = Some(self.worker.request_render(
+                                self.queue.clone(),
+                                base_img.clone(),
+                                self.base_generation,
+                                self.keep_steps,
+                            ));
                         }
-                        if ui.button("Save current render").clicked() && !render_request {
-                            if let Some(path) = rfd::FileDialog::new()
+                        if ui
+                            .add_enabled(
+                                *kind != RenderKind::Preview,
+                                Button::new("Save current render"),
+                            )
+                            .on_disabled_hover_text("Render the image to save it at full size")
+                            .clicked()
+                            && render_request.is_none()
+                            && let Some(path) = rfd::FileDialog::new()
                                 .set_title("Select path to save image")
                                 .save_file()
-                            {
-                                match img.save(path) {
-                                    Ok(_) => {
-                                        rfd::MessageDialog::new()
-                                            .set_title("Image savec")
-                                            .set_level(rfd::MessageLevel::Info)
-                                            .set_description("Image saved successfully")
-                                            .show();
-                                    }
-                                    Err(e) => {
-                                        rfd::MessageDialog::new()
-                                            .set_title("Image error")
-                                            .set_level(rfd::MessageLevel::Error)
-                                            .set_description(format!("Failed to save image: {e}"))
-                                            .show();
-                                    }
+                        {
+                            match batch::save_image(img, &path) {
+                                Ok(_) => {
+                                    rfd::MessageDialog::new()
+                                        .set_title("Image savec")
+                                        .set_level(rfd::MessageLevel::Info)
+                                        .set_description("Image saved successfully")
+                                        .show();
+                                }
+                                Err(e) => {
+                                    rfd::MessageDialog::new()
+                                        .set_title("Image error")
+                                        .set_level(rfd::MessageLevel::Error)
+                                        .set_description(format!("Failed to save image: {e}"))
+                                        .show();
                                 }
                             }
                         }
+                        ui.checkbox(&mut self.live_preview.enabled, "Live preview");
                     });
                 }
             }
-            if render_request {
+            if let Some(cancel) = render_request {
+                self.cancel_live_preview();
+                self.steps.clear();
+                self.step_hashes = self.queue.prefix_hashes();
+                let previous = std::mem::replace(&mut self.img, ImageLoadState::None);
                 self.img = ImageLoadState::Rendering {
                     progress: 0,
                     total: self.queue.len(),
+                    cancel,
+                    previous: Box::new(previous),
                 };
             }
         } else {
@@ -222,7 +754,7 @@ impl Application {
                 available - spacing.item_spacing.y * 5.
             };
             ui.allocate_ui(vec2(available_width, available_height), |ui| {
-                self.queue.ui(ui);
+                self.queue.ui(ui, &[]);
                 ui.add_space(ui.available_height());
             });
         }
@@ -236,18 +768,34 @@ enum ImageLoadState {
     Rendering {
         progress: usize,
         total: usize,
+        cancel: CancelToken,
+        previous: Box<ImageLoadState>,
     },
     Loaded {
         #[allow(dead_code)]
         handle: TextureHandle,
         tex: SizedTexture,
         img: DynamicImage,
+        kind: RenderKind,
     },
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RenderKind {
+    Full,
+    /// Downscaled live preview
+    Preview,
+    /// Result of a single step of the queue
+    Step(usize),
+}
+
 impl App for Application {
     fn update(&mut self, ctx: &eframe::egui::Context, _frame: &mut eframe::Frame) {
         self.update_image_state(ctx);
+        self.update_live_preview(ctx);
+        self.show_batch_window(ctx);
+        self.handle_history_shortcuts(ctx);
+        self.show_history_window(ctx);
         CentralPanel::default().show(ctx, |ui| {
             ui.heading("Ruin me image");
             ui.separator();
@@ -262,22 +810,52 @@ impl App for Application {
                         ui.label("Loading image...");
                     });
                 }
-                ImageLoadState::Rendering { progress, total } => {
+                ImageLoadState::Rendering {
+                    progress,
+                    total,
+                    cancel,
+                    ..
+                } => {
                     ui.horizontal(|ui| {
                         ui.spinner();
-                        ui.label("Rendering image...");
+                        if cancel.is_cancelled() {
+                            ui.label("Cancelling render...");
+                        } else {
+                            ui.label("Rendering image...");
+                        }
                     });
-                    let progress_percent = *progress as f32 / *total as f32;
+                    let done = *progress as f32 + cancel.step_progress();
+                    let progress_percent = done / *total as f32;
                     ProgressBar::new(progress_percent)
                         .animate(true)
                         .text(format!("{progress} / {total} filters processed"))
                         .ui(ui);
                 }
-                ImageLoadState::Loaded { tex, .. } => {
-                    ui.label("Image preview");
-                    Image::new(*tex).shrink_to_fit().ui(ui);
+                ImageLoadState::Loaded { tex, kind, .. } => {
+                    match kind {
+                        RenderKind::Full => ui.label("Image preview"),
+                        RenderKind::Preview => ui.label("Live preview (downscaled)"),
+                        RenderKind::Step(i) => ui.label(format!("Result of step {}", i + 1)),
+                    };
+                    if *kind == RenderKind::Preview && self.queue.is_scale_dependent() {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            "JPEG blocks and data bending look coarser than in the full render",
+                        );
+                    }
+                    if let Some(e) = &self.live_preview.error {
+                        ui.colored_label(
+                            ui.visuals().error_fg_color,
+                            format!("Preview failed: {e}"),
+                        );
+                    }
+                    let original = self.base_texture.as_ref().map(SizedTexture::from_handle);
+                    self.viewer.show(ui, *tex, original);
                 }
             });
         });
+        // Slider and drag value edits are merged until the widget is released
+        let dragging = ctx.dragged_id().is_some();
+        self.history.record(&self.queue, dragging);
     }
 }
@@ -0,0 +1,271 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use image::{DynamicImage, ImageError, ImageFormat, ImageResult};
+
+use crate::{
+    commands::{CancelToken, CommandQueue},
+    worker::WorkerError,
+};
+
+pub const DEFAULT_TEMPLATE: &str = "{name}_ruined.{ext}";
+
+pub struct BatchFailure {
+    pub path: PathBuf,
+    pub error: WorkerError,
+}
+
+fn is_image(path: &Path) -> bool {
+    path.is_file() && ImageFormat::from_path(path).is_ok()
+}
+
+/// Matches `*` and `?` wildcards, going back to the last `*` on a mismatch.
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` and of the name character it was last tried against
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn list_dir(dir: &Path, pattern: Option<&str>) -> io::Result<Vec<PathBuf>> {
+    let pattern: Option<Vec<char>> = pattern.map(|p| p.chars().collect());
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !is_image(&path) {
+            continue;
+        }
+        if let Some(pattern) = &pattern {
+            let name: Vec<char> = path
+                .file_name()
+                .map(|n| n.to_string_lossy().chars().collect())
+                .unwrap_or_default();
+            if !wildcard_match(pattern, &name) {
+                continue;
+            }
+        }
+        files.push(path);
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Expands every input into a list of image files.
+/// Inputs can be image files, folders, or file name patterns using `*` and `?` wildcards.
+pub fn collect_inputs(inputs: &[PathBuf]) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for input in inputs {
+        let file_name = input
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if input.is_dir() {
+            files.extend(list_dir(input, None)?);
+        } else if file_name.contains(['*', '?']) {
+            let parent = match input.parent() {
+                Some(p) if !p.as_os_str().is_empty() => p,
+                _ => Path::new("."),
+            };
+            files.extend(list_dir(parent, Some(&file_name))?);
+        } else {
+            files.push(input.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Builds the output path of an input file from a naming template.
+/// Supported placeholders are `{name}` (file name without extension), `{ext}` and `{index}` (starting at 1).
+pub fn output_path(output_dir: &Path, template: &str, input: &Path, index: usize) -> PathBuf {
+    let name = input
+        .file_stem()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or_default();
+    let ext = input
+        .extension()
+        .map(|s| s.to_string_lossy())
+        .unwrap_or("png".into());
+    let file_name = template
+        .replace("{name}", &name)
+        .replace("{ext}", &ext)
+        .replace("{index}", &(index + 1).to_string());
+    output_dir.join(file_name)
+}
+
+/// Resolves a path that may not exist yet, through its parent folder.
+fn absolute_path(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => parent
+            .canonicalize()
+            .map(|parent| parent.join(name))
+            .unwrap_or_else(|_| path.to_path_buf()),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Pairs every input with its output path, before anything is written.
+/// Inputs whose output would overwrite an input, or that share their output with another input, get an error instead.
+pub fn plan_outputs(
+    inputs: Vec<PathBuf>,
+    output_dir: &Path,
+    template: &str,
+) -> Vec<(PathBuf, Result<PathBuf, WorkerError>)> {
+    let outputs: Vec<PathBuf> = inputs
+        .iter()
+        .enumerate()
+        .map(|(i, input)| output_path(output_dir, template, input, i))
+        .collect();
+    let sources: HashSet<PathBuf> = inputs.iter().map(|p| absolute_path(p)).collect();
+    let mut targets: HashMap<PathBuf, usize> = HashMap::new();
+    for output in &outputs {
+        *targets.entry(absolute_path(output)).or_default() += 1;
+    }
+    inputs
+        .into_iter()
+        .zip(outputs)
+        .map(|(input, output)| {
+            let target = absolute_path(&output);
+            let output = if sources.contains(&target) {
+                Err(WorkerError::OverwritesInput(output))
+            } else if targets[&target] > 1 {
+                Err(WorkerError::DuplicateOutput(output))
+            } else {
+                Ok(output)
+            };
+            (input, output)
+        })
+        .collect()
+}
+
+/// Saves an image, keeping its pixel format when the output format supports it.
+/// Otherwise it falls back to 8 bits per channel, then to RGB (e.g. 16-bit PNG saved as JPEG).
+pub fn save_image(img: &DynamicImage, path: &Path) -> ImageResult<()> {
+    let res = match img.save(path) {
+        Err(ImageError::Unsupported(_)) if img.color().has_alpha() => {
+            DynamicImage::from(img.to_rgba8()).save(path)
+        }
+        Err(ImageError::Unsupported(_)) => img.to_rgb8().save(path),
+        res => return res,
+    };
+    match res {
+        Err(ImageError::Unsupported(_)) => img.to_rgb8().save(path),
+        res => res,
+    }
+}
+
+pub fn process_file(
+    queue: &CommandQueue,
+    input: &Path,
+    output: &Path,
+    cancel: &CancelToken,
+) -> Result<(), WorkerError> {
+    let img = image::open(input)?;
+    let img = queue.clone().execute(img, cancel, |_| {})?;
+    save_image(&img, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        wildcard_match(&pattern, &name)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*", "photo.png"));
+        assert!(matches("*", ""));
+        assert!(matches("*.png", "photo.png"));
+        assert!(!matches("*.png", "photo.jpg"));
+        assert!(matches("img_??.*", "img_01.jpg"));
+        assert!(!matches("img_??.*", "img_1.jpg"));
+        assert!(matches("a*b*c", "axxbyyc"));
+        assert!(!matches("a*b*c", "axxbyy"));
+        assert!(!matches("photo", "photo.png"));
+        assert!(matches("*a*", "banana"));
+        assert!(matches("?*", "x"));
+        assert!(!matches("?*", ""));
+        let long = "a".repeat(64);
+        assert!(!matches("a*a*a*a*a*a*a*a*a*a*b", &long));
+    }
+
+    #[test]
+    fn output_paths() {
+        let dir = Path::new("out");
+        assert_eq!(
+            output_path(dir, DEFAULT_TEMPLATE, Path::new("in/cat.jpg"), 0),
+            dir.join("cat_ruined.jpg")
+        );
+        assert_eq!(
+            output_path(dir, "{index}-{name}.png", Path::new("in/cat.jpg"), 4),
+            dir.join("5-cat.png")
+        );
+        // Files without an extension are saved as PNG
+        assert_eq!(
+            output_path(dir, "{name}.{ext}", Path::new("in/cat"), 0),
+            dir.join("cat.png")
+        );
+    }
+
+    #[test]
+    fn output_conflicts() {
+        let dir = std::env::temp_dir().join(format!("ruin-me-image-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let inputs = vec![dir.join("a.png"), dir.join("b.png"), dir.join("c.jpg")];
+        for input in &inputs {
+            fs::write(input, []).unwrap();
+        }
+
+        let plan = plan_outputs(inputs.clone(), &dir, "{name}.{ext}");
+        assert!(
+            plan.iter()
+                .all(|(_, output)| matches!(output, Err(WorkerError::OverwritesInput(_))))
+        );
+
+        let plan = plan_outputs(inputs.clone(), &dir, "{name}_ruined.png");
+        assert!(plan.iter().all(|(_, output)| output.is_ok()));
+
+        // Two different inputs would both be saved to "a.png"
+        let plan = plan_outputs(
+            vec![inputs[0].clone(), dir.join("sub/a.png")],
+            &dir.join("out"),
+            "{name}.png",
+        );
+        assert!(
+            plan.iter()
+                .all(|(_, output)| matches!(output, Err(WorkerError::DuplicateOutput(_))))
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::Arc;
+
+use image::DynamicImage;
+
+use crate::commands::{CancelToken, CommandQueue, QueueError};
+
+/// Maximum amount of pixel data kept in the cache, in bytes.
+const CACHE_BUDGET: usize = 1 << 30;
+
+/// Intermediate results of the last rendered queue, so that editing a step
+/// only re-runs the queue from that step onwards.
+/// The progress callback of renders also receives the image as of each step when available.
+#[derive(Default)]
+pub struct RenderCache {
+    proxy_size: Option<u32>,
+    /// Generation of the base image the entries were rendered from
+    generation: Option<u64>,
+    base_img: Option<Arc<DynamicImage>>,
+    proxy_img: Option<DynamicImage>,
+    entries: Vec<(u64, DynamicImage)>,
+}
+
+impl RenderCache {
+    /// Creates a cache rendering on a copy of the base image downscaled to fit in `size` x `size`.
+    pub fn with_proxy_size(size: u32) -> Self {
+        Self {
+            proxy_size: Some(size),
+            ..Default::default()
+        }
+    }
+
+    /// Dimensions of the image renders start from, the proxy if there is one.
+    pub fn source_dimensions(&self) -> Option<(u32, u32)> {
+        let img = self.proxy_img.as_ref().or(self.base_img.as_deref())?;
+        Some((img.width(), img.height()))
+    }
+
+    /// Cached result of the queue prefix with the given hash.
+    pub fn get(&self, hash: u64) -> Option<&DynamicImage> {
+        self.entries
+            .iter()
+            .find(|(h, _)| *h == hash)
+            .map(|(_, img)| img)
+    }
+
+    /// Renders the queue on `base_img`. `generation` identifies the base image, so that the
+    /// cache is only cleared when a different image is loaded.
+    /// Pixel parameters of the queue are scaled down along with the proxy.
+    pub fn render(
+        &mut self,
+        mut queue: CommandQueue,
+        base_img: Arc<DynamicImage>,
+        generation: u64,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(usize, Option<&DynamicImage>),
+    ) -> Result<DynamicImage, QueueError> {
+        if self.generation != Some(generation) {
+            self.entries.clear();
+            self.proxy_img = self
+                .proxy_size
+                .filter(|size| base_img.width() > *size || base_img.height() > *size)
+                .map(|size| base_img.thumbnail(size, size));
+            self.base_img = Some(base_img);
+            self.generation = Some(generation);
+        }
+        let base_img = self.base_img.as_deref().expect("base image should be set");
+        if let Some(proxy) = &self.proxy_img {
+            queue.scale_pixels(proxy.width() as f32 / base_img.width() as f32);
+        }
+        let source = self.proxy_img.as_ref().unwrap_or(base_img);
+        let hashes = queue.result_hashes();
+        let cached = hashes.iter().enumerate().rev().find_map(|(i, hash)| {
+            let hash = (*hash)?;
+            let (_, img) = self.entries.iter().find(|(h, _)| *h == hash)?;
+            Some((i + 1, img.clone()))
+        });
+        let (start, img) = cached.unwrap_or_else(|| (0, source.clone()));
+        if start > 0 {
+            // Report the cached steps, disabled ones having the same result as the previous step
+            let mut last = Some(source);
+            for (i, hash) in hashes[..start].iter().enumerate() {
+                if let Some(hash) = hash {
+                    last = self
+                        .entries
+                        .iter()
+                        .find(|(h, _)| h == hash)
+                        .map(|(_, img)| img);
+                }
+                progress(i + 1, last);
+            }
+        }
+
+        // Only keep results that are still part of the current queue
+        let kept = &hashes[..start];
+        self.entries.retain(|(h, _)| kept.contains(&Some(*h)));
+        let res = queue.execute_from(start, img, cancel, |step, img| {
+            if let Some(hash) = hashes[step - 1] {
+                self.entries.push((hash, img.clone()));
+            }
+            progress(step, Some(img));
+        });
+
+        let mut size: usize = self
+            .entries
+            .iter()
+            .map(|(_, img)| img.as_bytes().len())
+            .sum();
+        // Entries are in queue order. Editing a step invalidates every later result, so the
+        // last steps are the least likely to be reused.
+        while size > CACHE_BUDGET
+            && let Some((_, img)) = self.entries.pop()
+        {
+            size -= img.as_bytes().len();
+        }
+        res
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    batch::{self, BatchFailure},
+    commands::{CancelToken, CommandQueue},
+};
+
+type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
+
+const USAGE: &str = "\
+Usage:
+    ruin-me-image
+        Launch the graphical interface
+    ruin-me-image apply --queue <FILE> --input <FILE> --output <FILE>
+        Apply a saved queue to an image and write the result
+    ruin-me-image batch --queue <FILE> --output-dir <DIR> [--name <TEMPLATE>] <INPUT>...
+        Apply a saved queue to every image in the given files, folders or patterns (e.g. \"thumbs/*.png\")
+        The name template supports {name}, {ext} and {index} (default: \"{name}_ruined.{ext}\")
+    ruin-me-image help
+        Show this message";
+
+struct ApplyArgs {
+    queue: PathBuf,
+    input: PathBuf,
+    output: PathBuf,
+}
+
+fn parse_apply_args(args: &[String]) -> BoxResult<ApplyArgs> {
+    let mut queue = None;
+    let mut input = None;
+    let mut output = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "-q" | "--queue" => &mut queue,
+            "-i" | "--input" => &mut input,
+            "-o" | "--output" => &mut output,
+            other => return Err(format!("unknown argument: {other}").into()),
+        };
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {arg}"))?;
+        *slot = Some(PathBuf::from(value));
+    }
+    Ok(ApplyArgs {
+        queue: queue.ok_or("missing --queue")?,
+        input: input.ok_or("missing --input")?,
+        output: output.ok_or("missing --output")?,
+    })
+}
+
+struct BatchArgs {
+    queue: PathBuf,
+    output_dir: PathBuf,
+    template: String,
+    inputs: Vec<PathBuf>,
+}
+
+fn parse_batch_args(args: &[String]) -> BoxResult<BatchArgs> {
+    let mut queue = None;
+    let mut output_dir = None;
+    let mut template = None;
+    let mut inputs = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let slot = match arg.as_str() {
+            "-q" | "--queue" => &mut queue,
+            "-o" | "--output-dir" => &mut output_dir,
+            "-n" | "--name" => &mut template,
+            other if other.starts_with('-') => {
+                return Err(format!("unknown argument: {other}").into());
+            }
+            other => {
+                inputs.push(PathBuf::from(other));
+                continue;
+            }
+        };
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {arg}"))?;
+        *slot = Some(value.clone());
+    }
+    if inputs.is_empty() {
+        return Err("no input given".into());
+    }
+    Ok(BatchArgs {
+        queue: queue.ok_or("missing --queue")?.into(),
+        output_dir: output_dir.ok_or("missing --output-dir")?.into(),
+        template: template.unwrap_or(batch::DEFAULT_TEMPLATE.into()),
+        inputs,
+    })
+}
+
+fn load_queue(path: &Path) -> BoxResult<CommandQueue> {
+    let contents = fs::read_to_string(path)?;
+    let mut queue = CommandQueue::default();
+    queue.deserialize(&contents)?;
+    Ok(queue)
+}
+
+fn apply(args: ApplyArgs) -> BoxResult<()> {
+    let queue = load_queue(&args.queue)?;
+    println!(
+        "Applying {} filters to {}",
+        queue.len(),
+        args.input.display()
+    );
+    batch::process_file(&queue, &args.input, &args.output, &CancelToken::default())?;
+    println!("Saved result to {}", args.output.display());
+    Ok(())
+}
+
+fn run_batch(args: BatchArgs) -> BoxResult<()> {
+    let queue = load_queue(&args.queue)?;
+    let inputs = batch::collect_inputs(&args.inputs)?;
+    if inputs.is_empty() {
+        return Err("no images found in the given inputs".into());
+    }
+    fs::create_dir_all(&args.output_dir)?;
+    let total = inputs.len();
+    println!("Applying {} filters to {total} images", queue.len());
+    let mut failures = vec![];
+    let plan = batch::plan_outputs(inputs, &args.output_dir, &args.template);
+    for (i, (input, output)) in plan.into_iter().enumerate() {
+        let res = output.and_then(|output| {
+            batch::process_file(&queue, &input, &output, &CancelToken::default())?;
+            Ok(output)
+        });
+        match res {
+            Ok(output) => println!(
+                "[{}/{total}] {} -> {}",
+                i + 1,
+                input.display(),
+                output.display()
+            ),
+            Err(error) => {
+                eprintln!("[{}/{total}] {}: {error}", i + 1, input.display());
+                failures.push(BatchFailure { path: input, error });
+            }
+        }
+    }
+    println!("Processed {total} images, {} failed", failures.len());
+    if !failures.is_empty() {
+        for failure in &failures {
+            eprintln!("  {}: {}", failure.path.display(), failure.error);
+        }
+        return Err(format!("{} of {total} images failed", failures.len()).into());
+    }
+    Ok(())
+}
+
+/// Runs the command line interface if any arguments were given, returning the exit code.
+/// Returns `None` when the graphical interface should be launched instead.
+pub fn run(args: &[String]) -> Option<i32> {
+    let (command, rest) = args.split_first()?;
+    let res = match command.as_str() {
+        "apply" => parse_apply_args(rest).and_then(apply),
+        "batch" => parse_batch_args(rest).and_then(run_batch),
+        "help" | "-h" | "--help" => {
+            println!("{USAGE}");
+            return Some(0);
+        }
+        other => {
+            eprintln!("Unknown command: {other}\n\n{USAGE}");
+            return Some(2);
+        }
+    };
+    match res {
+        Ok(_) => Some(0),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            Some(1)
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use image::Rgba32FImage;
+use rayon::prelude::*;
+
+use super::{CancelToken, FilterError};
+
+/// Number of rows processed between checks for cancellation.
+const BAND_ROWS: usize = 32;
+
+/// Normalized weights of a Gaussian kernel from `-radius` to `radius`.
+fn kernel(sigma: f32) -> Vec<f32> {
+    let radius = (3. * sigma).ceil() as i32;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|x| (-0.5 * (x as f32 / sigma).powi(2)).exp())
+        .collect();
+    let sum: f32 = weights.iter().sum();
+    weights.into_iter().map(|w| w / sum).collect()
+}
+
+/// Runs `f` on every row of `out` in parallel, a band of rows at a time so the pass can be
+/// cancelled. Progress goes from `progress.0` to `progress.1` over the pass.
+fn rows(
+    out: &mut [f32],
+    width: usize,
+    cancel: &CancelToken,
+    progress: (f32, f32),
+    f: impl Fn(usize, &mut [f32]) + Sync,
+) -> Result<(), FilterError> {
+    let bands = out.len().div_ceil(width * 4 * BAND_ROWS);
+    for (i, band) in out.chunks_mut(width * 4 * BAND_ROWS).enumerate() {
+        cancel.check()?;
+        cancel.set_step_progress(progress.0 + (progress.1 - progress.0) * i as f32 / bands as f32);
+        band.par_chunks_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| f(i * BAND_ROWS + y, row));
+    }
+    Ok(())
+}
+
+/// Separable Gaussian blur of every channel, the edges of the image being extended.
+/// A zero `sigma` blurs with a sigma of 0.8 like `imageops::blur`, which panics on negative ones.
+pub fn gaussian(
+    img: &mut Rgba32FImage,
+    sigma: f32,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let sigma = if sigma == 0. { 0.8 } else { sigma };
+    if !(sigma.is_finite() && sigma > 0.) {
+        return Err(FilterError::InvalidSigma(sigma));
+    }
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let kernel = kernel(sigma);
+    let radius = (kernel.len() / 2) as isize;
+
+    let source = img.as_raw().clone();
+    rows(img, width, cancel, (0., 0.5), |y, row| {
+        let line = &source[y * width * 4..(y + 1) * width * 4];
+        for (x, px) in row.chunks_exact_mut(4).enumerate() {
+            let mut sum = [0.; 4];
+            for (k, weight) in kernel.iter().enumerate() {
+                let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1) as usize;
+                for c in 0..4 {
+                    sum[c] += line[sx * 4 + c] * weight;
+                }
+            }
+            px.copy_from_slice(&sum);
+        }
+    })?;
+
+    let source = img.as_raw().clone();
+    rows(img, width, cancel, (0.5, 1.), |y, row| {
+        row.fill(0.);
+        for (k, weight) in kernel.iter().enumerate() {
+            let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1) as usize;
+            let line = &source[sy * width * 4..(sy + 1) * width * 4];
+            for (v, s) in row.iter_mut().zip(line) {
+                *v += s * weight;
+            }
+        }
+    })
+}
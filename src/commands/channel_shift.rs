@@ -0,0 +1,140 @@
+use eframe::egui::{DragValue, RadioButton, Ui, Widget};
+use image::Rgba32FImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+const CHANNELS: [&str; 3] = ["Red", "Green", "Blue"];
+
+/// How the red, green and blue channels are moved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShiftMode {
+    /// Moves every channel by `[x, y]` pixels
+    Offset { channels: [[i32; 2]; 3] },
+    /// Scales every channel from the centre of the image, like the chromatic aberration
+    /// of a cheap lens
+    Radial { scales: [f32; 3] },
+}
+
+/// What shows up where a channel moved away from the edge of the image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum EdgeMode {
+    /// The other side of the image
+    #[default]
+    Wrap,
+    /// The closest pixel of the image
+    Clamp,
+}
+
+impl EdgeMode {
+    fn index(self, i: i64, size: u32) -> u32 {
+        match self {
+            Self::Wrap => i.rem_euclid(size as i64) as u32,
+            Self::Clamp => i.clamp(0, size as i64 - 1) as u32,
+        }
+    }
+}
+
+/// Settings UI of the `ChannelShift` filter.
+pub fn ui(ui: &mut Ui, mode: &mut ShiftMode, edges: &mut EdgeMode) {
+    ui.horizontal(|ui| {
+        if ui
+            .add(RadioButton::new(
+                matches!(mode, ShiftMode::Offset { .. }),
+                "Offset",
+            ))
+            .clicked()
+        {
+            *mode = ShiftMode::Offset {
+                channels: [[4, 0], [0, 0], [-4, 0]],
+            };
+        }
+        if ui
+            .add(RadioButton::new(
+                matches!(mode, ShiftMode::Radial { .. }),
+                "Radial",
+            ))
+            .clicked()
+        {
+            *mode = ShiftMode::Radial {
+                scales: [1.01, 1., 0.99],
+            };
+        }
+    });
+    match mode {
+        ShiftMode::Offset { channels } => {
+            for (offset, name) in channels.iter_mut().zip(CHANNELS) {
+                ui.horizontal(|ui| {
+                    for v in offset {
+                        DragValue::new(v).suffix("px").ui(ui);
+                    }
+                    ui.label(name);
+                });
+            }
+        }
+        ShiftMode::Radial { scales } => {
+            for (scale, name) in scales.iter_mut().zip(CHANNELS) {
+                ui.horizontal(|ui| {
+                    DragValue::new(scale).speed(0.001).range(0.5..=2.0).ui(ui);
+                    ui.label(format!("{name} scale"));
+                });
+            }
+        }
+    }
+    ui.horizontal(|ui| {
+        ui.label("Edges");
+        ui.radio_value(edges, EdgeMode::Wrap, "Wrap");
+        ui.radio_value(edges, EdgeMode::Clamp, "Clamp");
+    });
+}
+
+/// Moves the colour channels of the image independently, leaving alpha in place.
+pub fn shift(
+    img: &mut Rgba32FImage,
+    mode: ShiftMode,
+    edges: EdgeMode,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let source = img.clone();
+    let (cx, cy) = (width as f32 / 2., height as f32 / 2.);
+    img.par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            cancel.check()?;
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let (x, y) = (x as i64, y as i64);
+                for c in 0..3 {
+                    px[c] = match mode {
+                        ShiftMode::Offset { channels } => {
+                            let [dx, dy] = channels[c];
+                            let sx = edges.index(x - dx as i64, width);
+                            let sy = edges.index(y - dy as i64, height);
+                            source.get_pixel(sx, sy)[c]
+                        }
+                        ShiftMode::Radial { scales } => {
+                            // Where the pixel was before scaling
+                            let scale = scales[c].max(f32::EPSILON);
+                            let sx = (x as f32 + 0.5 - cx) / scale + cx - 0.5;
+                            let sy = (y as f32 + 0.5 - cy) / scale + cy - 0.5;
+                            let (x0, y0) = (sx.floor(), sy.floor());
+                            let (fx, fy) = (sx - x0, sy - y0);
+                            let value = |dx: i64, dy: i64| {
+                                let px = edges.index(x0 as i64 + dx, width);
+                                let py = edges.index(y0 as i64 + dy, height);
+                                source.get_pixel(px, py)[c]
+                            };
+                            let top = value(0, 0) * (1. - fx) + value(1, 0) * fx;
+                            let bottom = value(0, 1) * (1. - fx) + value(1, 1) * fx;
+                            top * (1. - fy) + bottom * fy
+                        }
+                    };
+                }
+            }
+            Ok::<_, FilterError>(())
+        })
+}
@@ -0,0 +1,414 @@
+use std::{ops::Range, panic};
+
+use eframe::egui::{RadioButton, Slider, Ui, Widget};
+use image::{
+    DynamicImage, ImageFormat,
+    codecs::{
+        bmp::BmpEncoder,
+        jpeg::JpegEncoder,
+        png::{self, PngEncoder},
+    },
+};
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+
+use super::FilterError;
+
+/// Number of times the bytes are corrupted again when the result can't be decoded.
+const ATTEMPTS: usize = 8;
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// File format the image goes through while it is corrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BendFormat {
+    Jpeg {
+        quality: u8,
+    },
+    Bmp,
+    /// PNG with uncompressed image data, so corrupted bytes map to pixels
+    Png,
+}
+
+impl BendFormat {
+    fn image_format(self) -> ImageFormat {
+        match self {
+            Self::Jpeg { .. } => ImageFormat::Jpeg,
+            Self::Bmp => ImageFormat::Bmp,
+            Self::Png => ImageFormat::Png,
+        }
+    }
+}
+
+/// Which changes are applied to the bytes, one being picked at random for every corruption.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BendOperations {
+    /// Flip a single bit
+    pub flip: bool,
+    /// Move all the following bytes, like a byte was deleted
+    pub shift: bool,
+    /// Copy a run of bytes over the next ones
+    pub repeat: bool,
+    /// Set a run of bytes to zero
+    pub zero: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operation {
+    Flip,
+    Shift,
+    Repeat,
+    Zero,
+}
+
+/// Settings UI of the `DataBend` filter.
+pub fn ui(
+    ui: &mut Ui,
+    format: &mut BendFormat,
+    corruptions: &mut u32,
+    operations: &mut BendOperations,
+) {
+    ui.horizontal(|ui| {
+        if ui
+            .add(RadioButton::new(
+                matches!(format, BendFormat::Jpeg { .. }),
+                "JPEG",
+            ))
+            .clicked()
+        {
+            *format = BendFormat::Jpeg { quality: 75 };
+        }
+        ui.radio_value(format, BendFormat::Bmp, "BMP");
+        ui.radio_value(format, BendFormat::Png, "PNG");
+    });
+    if let BendFormat::Jpeg { quality } = format {
+        Slider::new(quality, 1..=100).text("Quality").ui(ui);
+    }
+    Slider::new(corruptions, 1..=1000)
+        .logarithmic(true)
+        .text("Corruptions")
+        .ui(ui);
+    ui.horizontal(|ui| {
+        ui.checkbox(&mut operations.flip, "Flip");
+        ui.checkbox(&mut operations.shift, "Shift");
+        ui.checkbox(&mut operations.repeat, "Repeat");
+        ui.checkbox(&mut operations.zero, "Zero");
+    });
+}
+
+fn encode(img: &DynamicImage, format: BendFormat) -> Result<Vec<u8>, FilterError> {
+    let mut bytes = Vec::new();
+    match format {
+        BendFormat::Jpeg { quality } => {
+            img.write_with_encoder(JpegEncoder::new_with_quality(&mut bytes, quality))?
+        }
+        BendFormat::Bmp => img.write_with_encoder(BmpEncoder::new(&mut bytes))?,
+        BendFormat::Png => img.write_with_encoder(PngEncoder::new_with_quality(
+            &mut bytes,
+            png::CompressionType::Uncompressed,
+            png::FilterType::NoFilter,
+        ))?,
+    }
+    Ok(bytes)
+}
+
+/// Entropy coded data of a baseline JPEG file, between the start of scan and end of image markers.
+fn jpeg_payload(bytes: &[u8]) -> Option<Range<usize>> {
+    let mut i = 2;
+    while i + 4 <= bytes.len() && bytes[i] == 0xFF {
+        let len = u16::from_be_bytes([bytes[i + 2], bytes[i + 3]]) as usize;
+        if bytes[i + 1] == 0xDA {
+            return Some((i + 2 + len).min(bytes.len())..bytes.len().saturating_sub(2));
+        }
+        i += 2 + len;
+    }
+    None
+}
+
+/// Pixel data of a BMP file, after the headers and the palette.
+fn bmp_payload(bytes: &[u8]) -> Option<Range<usize>> {
+    let offset = u32::from_le_bytes(bytes.get(10..14)?.try_into().ok()?) as usize;
+    (offset <= bytes.len()).then_some(offset..bytes.len())
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in bytes.chunks(5552) {
+        for &v in chunk {
+            a += v as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Runs `corrupt` on the filtered scanlines of an uncompressed PNG file and rebuilds the
+/// file around them, with valid checksums and filter types.
+fn bend_png(bytes: &[u8], corrupt: impl FnOnce(&mut [u8])) -> Option<Vec<u8>> {
+    let mut head = PNG_SIGNATURE.to_vec();
+    let mut zlib = Vec::new();
+    let mut stride = 0;
+    let mut i = PNG_SIGNATURE.len();
+    while i + 12 <= bytes.len() {
+        let len = u32::from_be_bytes(bytes[i..i + 4].try_into().ok()?) as usize;
+        let kind = &bytes[i + 4..i + 8];
+        let data = bytes.get(i + 8..i + 8 + len)?;
+        match kind {
+            b"IHDR" => {
+                let width = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+                let channels = match data.get(9)? {
+                    0 => 1,
+                    2 => 3,
+                    4 => 2,
+                    6 => 4,
+                    _ => return None,
+                };
+                stride = 1 + width * channels * (*data.get(8)? as usize).div_ceil(8);
+                head.extend_from_slice(&bytes[i..i + 12 + len]);
+            }
+            b"IDAT" => zlib.extend_from_slice(data),
+            b"IEND" => break,
+            _ => head.extend_from_slice(&bytes[i..i + 12 + len]),
+        }
+        i += 12 + len;
+    }
+
+    // Stored deflate blocks, each with a 5 byte header, after the 2 byte zlib header
+    let mut scanlines = Vec::new();
+    let mut j = 2;
+    loop {
+        let header = *zlib.get(j)?;
+        if header & 0b110 != 0 {
+            return None;
+        }
+        let len = u16::from_le_bytes(zlib.get(j + 1..j + 3)?.try_into().ok()?) as usize;
+        scanlines.extend_from_slice(zlib.get(j + 5..j + 5 + len)?);
+        j += 5 + len;
+        if header & 1 == 1 {
+            break;
+        }
+    }
+
+    corrupt(&mut scanlines);
+    if stride > 0 {
+        // Every row starts with its filter type, out of range values would make the file invalid
+        for row in scanlines.chunks_mut(stride) {
+            row[0] %= 5;
+        }
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = scanlines.chunks(u16::MAX as usize).collect();
+    for (k, block) in blocks.iter().enumerate() {
+        let len = block.len() as u16;
+        zlib.push((k + 1 == blocks.len()) as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&scanlines).to_be_bytes());
+    write_chunk(&mut head, b"IDAT", &zlib);
+    write_chunk(&mut head, b"IEND", &[]);
+    Some(head)
+}
+
+/// Applies `corruptions` random changes to the bytes.
+fn corrupt(
+    data: &mut [u8],
+    corruptions: u32,
+    operations: BendOperations,
+    random: &mut ChaCha20Rng,
+) {
+    let enabled: Vec<Operation> = [
+        (operations.flip, Operation::Flip),
+        (operations.shift, Operation::Shift),
+        (operations.repeat, Operation::Repeat),
+        (operations.zero, Operation::Zero),
+    ]
+    .into_iter()
+    .filter_map(|(on, op)| on.then_some(op))
+    .collect();
+    if data.is_empty() || enabled.is_empty() {
+        return;
+    }
+    // Runs grow with the file, so the damage stays visible on large images
+    let max_len = (data.len() / 2000).clamp(1, 4096);
+    for _ in 0..corruptions {
+        let start = random.random_range(0..data.len());
+        let len = random.random_range(1..=max_len).min(data.len() - start);
+        match enabled[random.random_range(0..enabled.len())] {
+            Operation::Flip => data[start] ^= 1 << random.random_range(0..8),
+            Operation::Shift => {
+                let rest = &mut data[start..];
+                let by = random.random_range(1..=max_len) % rest.len();
+                rest.rotate_left(by);
+            }
+            Operation::Repeat => {
+                let end = (start + 2 * len).min(data.len());
+                data.copy_within(start..end - len, start + len);
+            }
+            Operation::Zero => data[start..start + len].fill(0),
+        }
+    }
+}
+
+/// Encodes the image in `format`, corrupts the encoded image data and decodes it back.
+/// Headers are left alone so the file stays readable. Fails if the corrupted file still can't
+/// be decoded after a few attempts.
+pub fn bend(
+    img: &DynamicImage,
+    format: BendFormat,
+    corruptions: u32,
+    operations: BendOperations,
+    random: &mut ChaCha20Rng,
+    transparency: bool,
+) -> Result<DynamicImage, FilterError> {
+    // JPEG has no alpha channel
+    let alpha = transparency && !matches!(format, BendFormat::Jpeg { .. });
+    let img: DynamicImage = if alpha {
+        img.to_rgba8().into()
+    } else {
+        img.to_rgb8().into()
+    };
+    let clean = encode(&img, format)?;
+    for _ in 0..ATTEMPTS {
+        let mut bytes = clean.clone();
+        let bent = match format {
+            BendFormat::Png => bend_png(&bytes, |data| {
+                corrupt(data, corruptions, operations, random)
+            }),
+            BendFormat::Jpeg { .. } | BendFormat::Bmp => {
+                let payload = match format {
+                    BendFormat::Bmp => bmp_payload(&bytes),
+                    _ => jpeg_payload(&bytes),
+                };
+                payload.map(|payload| {
+                    corrupt(&mut bytes[payload], corruptions, operations, random);
+                    bytes
+                })
+            }
+        };
+        let Some(bent) = bent else {
+            break;
+        };
+        // Decoders aren't hardened against every malformed file, a panic counts as a failed attempt
+        let decoded = panic::catch_unwind(|| {
+            image::load_from_memory_with_format(&bent, format.image_format())
+        });
+        if let Ok(Ok(out)) = decoded
+            && out.width() == img.width()
+            && out.height() == img.height()
+        {
+            return Ok(out);
+        }
+    }
+    Err(FilterError::UndecodableBend { attempts: ATTEMPTS })
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{GenericImageView, RgbImage};
+    use rand::SeedableRng;
+
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        RgbImage::from_fn(37, 23, |x, y| {
+            image::Rgb([(x * 7) as u8, (y * 11) as u8, (x * y) as u8])
+        })
+        .into()
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        assert_eq!(adler32(b""), 1);
+        // Long enough for the sums to be reduced several times
+        let long = vec![0xFF; 20_000];
+        let (a, b) = long.iter().fold((1u64, 0u64), |(a, b), &v| {
+            let a = (a + v as u64) % 65521;
+            (a, (b + a) % 65521)
+        });
+        assert_eq!(adler32(&long), ((b << 16) | a) as u32);
+    }
+
+    #[test]
+    fn png_rebuild_without_corruption_keeps_the_image() {
+        let img = gradient();
+        let bytes = encode(&img, BendFormat::Png).unwrap();
+        let mut len = 0;
+        let rebuilt = bend_png(&bytes, |data| len = data.len()).unwrap();
+        // Filter type byte at the start of every row
+        assert_eq!(len, 23 * (1 + 37 * 3));
+        let out = image::load_from_memory_with_format(&rebuilt, ImageFormat::Png).unwrap();
+        assert_eq!(out.to_rgb8(), img.to_rgb8());
+    }
+
+    #[test]
+    fn png_rebuild_fixes_filter_types() {
+        let bytes = encode(&gradient(), BendFormat::Png).unwrap();
+        let rebuilt = bend_png(&bytes, |data| data.fill(0xFF)).unwrap();
+        let out = image::load_from_memory_with_format(&rebuilt, ImageFormat::Png).unwrap();
+        assert_eq!(out.dimensions(), (37, 23));
+    }
+
+    #[test]
+    fn payloads_skip_the_headers() {
+        let img = gradient();
+        let bmp = encode(&img, BendFormat::Bmp).unwrap();
+        let payload = bmp_payload(&bmp).unwrap();
+        assert_eq!(payload.end, bmp.len());
+        // 24 bit rows padded to 4 bytes
+        assert_eq!(payload.len(), 23 * (37 * 3usize).next_multiple_of(4));
+        assert_eq!(bmp_payload(&bmp[..8]), None);
+
+        let jpeg = encode(&img, BendFormat::Jpeg { quality: 90 }).unwrap();
+        let payload = jpeg_payload(&jpeg).unwrap();
+        assert_eq!(&jpeg[payload.end..], &[0xFF, 0xD9]);
+        assert!(jpeg[..payload.start].windows(2).any(|w| w == [0xFF, 0xDA]));
+        assert_eq!(jpeg_payload(&jpeg[..20]), None);
+    }
+
+    #[test]
+    fn bends_keep_the_size() {
+        let operations = BendOperations {
+            flip: true,
+            shift: true,
+            repeat: true,
+            zero: true,
+        };
+        for format in [
+            BendFormat::Jpeg { quality: 75 },
+            BendFormat::Bmp,
+            BendFormat::Png,
+        ] {
+            let mut random = ChaCha20Rng::seed_from_u64(3);
+            let out = bend(&gradient(), format, 5, operations, &mut random, false).unwrap();
+            assert_eq!(out.dimensions(), (37, 23));
+        }
+    }
+}
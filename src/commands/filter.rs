@@ -1,38 +1,542 @@
-use std::io::{BufWriter, Cursor};
+use std::fmt;
 
 use eframe::egui::{
-    DragValue, RadioButton, Slider, Ui, Widget,
+    ComboBox, DragValue, RadioButton, Slider, Ui, Widget,
     ecolor::{hsv_from_rgb, rgb_from_hsv},
 };
-use image::{
-    DynamicImage, GenericImage, GenericImageView, Pixel, Rgba,
-    codecs::jpeg::{JpegDecoder, JpegEncoder},
-};
+use image::{ColorType, DynamicImage, GrayImage, ImageError, imageops::FilterType};
 use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use super::{
+    CancelToken, blur,
+    channel_shift::{self, EdgeMode, ShiftMode},
+    databend::{self, BendFormat, BendOperations},
+    geometry::{self, CropRect},
+    gif,
+    jpeg::{self, ChromaSubsampling, JpegDrift, JpegSettings, QuantizationTables},
+    noise::{NoiseGenerator, NoiseModel},
+    pixel_sort::{self, PixelKey, SortDirection},
+    pixelate::{self, Averaging, CellShape},
+    quantize::{self, Dithering, PaletteSource},
+    scanline::{self, ScanlineSettings},
+};
+
+/// Channel type of the pixel buffers processed by [`map_rows`].
+trait Sample: Copy + Send + Sync {
+    /// Value of a fully saturated channel.
+    const MAX: f32;
+    fn to_f32(self) -> f32;
+    /// Converts back, saturating to the range of the type.
+    fn from_f32(v: f32) -> Self;
+}
+
+impl Sample for u8 {
+    const MAX: f32 = u8::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(v: f32) -> Self {
+        // Truncated rather than rounded, as the 8-bit filters always did
+        v as u8
+    }
+}
+
+impl Sample for u16 {
+    const MAX: f32 = u16::MAX as f32;
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+    fn from_f32(v: f32) -> Self {
+        v.round() as u16
+    }
+}
+
+impl Sample for f32 {
+    const MAX: f32 = 1.;
+    fn to_f32(self) -> f32 {
+        self
+    }
+    fn from_f32(v: f32) -> Self {
+        v
+    }
+}
+
+/// Fill colour of the geometric filters for an image of the given pixel format. Images
+/// without alpha keep their format, so they are filled with the opaque colour.
+fn fill_color(fill: [u8; 4], color: ColorType) -> [f32; 4] {
+    let mut fill = fill.map(|c| c as f32 / 255.);
+    if !color.has_alpha() {
+        fill[3] = 1.;
+    }
+    fill
+}
+
+/// Converts an image to the given pixel format, keeping it as is when it already matches.
+fn convert_to(img: DynamicImage, color: ColorType) -> DynamicImage {
+    if img.color() == color {
+        return img;
+    }
+    match color {
+        ColorType::L8 => img.into_luma8().into(),
+        ColorType::La8 => img.into_luma_alpha8().into(),
+        ColorType::Rgb8 => img.into_rgb8().into(),
+        ColorType::Rgba8 => img.into_rgba8().into(),
+        ColorType::L16 => img.into_luma16().into(),
+        ColorType::La16 => img.into_luma_alpha16().into(),
+        ColorType::Rgb16 => img.into_rgb16().into(),
+        ColorType::Rgba16 => img.into_rgba16().into(),
+        ColorType::Rgb32F => img.into_rgb32f().into(),
+        _ => img.into_rgba32f().into(),
+    }
+}
+
+fn map_buffer<S: Sample>(
+    buf: &mut [S],
+    width: u32,
+    channels: usize,
+    cancel: &CancelToken,
+    f: impl Fn(u32, &mut [f32], usize, f32) + Sync,
+) -> Result<(), FilterError> {
+    let stride = width as usize * channels;
+    if stride == 0 {
+        return Ok(());
+    }
+    buf.par_chunks_mut(stride)
+        .enumerate()
+        .try_for_each_init(Vec::new, |values, (y, row)| {
+            cancel.check()?;
+            values.clear();
+            values.extend(row.iter().map(|c| c.to_f32()));
+            f(y as u32, values, channels, S::MAX);
+            for (c, v) in row.iter_mut().zip(values.iter()) {
+                *c = S::from_f32(*v);
+            }
+            Ok(())
+        })
+}
+
+/// Runs `f` on every row of the image in parallel, in the native bit depth of the image.
+/// `f` receives the row index, the RGB(A) channel values of the row, the number of channels
+/// per pixel and the value of a saturated channel. Grayscale images are processed as RGB(A)
+/// of the same depth and converted back afterwards.
+fn map_rows(
+    img: DynamicImage,
+    cancel: &CancelToken,
+    f: impl Fn(u32, &mut [f32], usize, f32) + Sync,
+) -> Result<DynamicImage, FilterError> {
+    let color = img.color();
+    let working = match color {
+        ColorType::L8 => ColorType::Rgb8,
+        ColorType::La8 => ColorType::Rgba8,
+        ColorType::L16 => ColorType::Rgb16,
+        ColorType::La16 => ColorType::Rgba16,
+        color => color,
+    };
+    let mut img = convert_to(img, working);
+    let width = img.width();
+    match &mut img {
+        DynamicImage::ImageRgb8(buf) => map_buffer(buf, width, 3, cancel, f)?,
+        DynamicImage::ImageRgba8(buf) => map_buffer(buf, width, 4, cancel, f)?,
+        DynamicImage::ImageRgb16(buf) => map_buffer(buf, width, 3, cancel, f)?,
+        DynamicImage::ImageRgba16(buf) => map_buffer(buf, width, 4, cancel, f)?,
+        DynamicImage::ImageRgb32F(buf) => map_buffer(buf, width, 3, cancel, f)?,
+        DynamicImage::ImageRgba32F(buf) => map_buffer(buf, width, 4, cancel, f)?,
+        _ => {
+            let mut buf = img.to_rgba32f();
+            map_buffer(&mut buf, width, 4, cancel, f)?;
+            img = buf.into();
+        }
+    }
+    Ok(convert_to(img, color))
+}
+
+/// Copies the alpha channel of `original` into `img` if they have the same size,
+/// converting `img` back to the pixel format of `original`.
+fn restore_alpha(img: DynamicImage, original: &DynamicImage) -> DynamicImage {
+    fn copy<S: Copy>(dst: &mut [S], src: &[S], channels: usize) {
+        for (d, s) in dst
+            .chunks_exact_mut(channels)
+            .zip(src.chunks_exact(channels))
+        {
+            d[channels - 1] = s[channels - 1];
+        }
+    }
+    if img.width() != original.width() || img.height() != original.height() {
+        return img;
+    }
+    let mut img = convert_to(img, original.color());
+    match (&mut img, original) {
+        (DynamicImage::ImageLumaA8(d), DynamicImage::ImageLumaA8(s)) => copy(d, s, 2),
+        (DynamicImage::ImageRgba8(d), DynamicImage::ImageRgba8(s)) => copy(d, s, 4),
+        (DynamicImage::ImageLumaA16(d), DynamicImage::ImageLumaA16(s)) => copy(d, s, 2),
+        (DynamicImage::ImageRgba16(d), DynamicImage::ImageRgba16(s)) => copy(d, s, 4),
+        (DynamicImage::ImageRgba32F(d), DynamicImage::ImageRgba32F(s)) => copy(d, s, 4),
+        _ => {}
+    }
+    img
+}
+
+fn invert_alpha(img: &mut DynamicImage) {
+    fn invert<S: Sample>(buf: &mut [S], channels: usize) {
+        for px in buf.chunks_exact_mut(channels) {
+            let a = &mut px[channels - 1];
+            *a = S::from_f32(S::MAX - a.to_f32());
+        }
+    }
+    match img {
+        DynamicImage::ImageLumaA8(buf) => invert(buf, 2),
+        DynamicImage::ImageRgba8(buf) => invert(buf, 4),
+        DynamicImage::ImageLumaA16(buf) => invert(buf, 2),
+        DynamicImage::ImageRgba16(buf) => invert(buf, 4),
+        DynamicImage::ImageRgba32F(buf) => invert(buf, 4),
+        _ => {}
+    }
+}
+
+/// Blends the image over an opaque background, giving an image of the same depth without alpha.
+fn composite(img: DynamicImage, background: [u8; 3]) -> DynamicImage {
+    let target = match img.color() {
+        ColorType::La8 | ColorType::Rgba8 => ColorType::Rgb8,
+        ColorType::La16 | ColorType::Rgba16 => ColorType::Rgb16,
+        _ => ColorType::Rgb32F,
+    };
+    let background = background.map(|c| c as f32 / 255.);
+    let mut rgba = img.into_rgba32f();
+    rgba.par_chunks_mut(4).for_each(|px| {
+        let a = px[3];
+        for (c, bg) in px.iter_mut().zip(background) {
+            *c = *c * a + bg * (1. - a);
+        }
+        px[3] = 1.;
+    });
+    convert_to(rgba.into(), target)
+}
+
+fn seed_ui(ui: &mut Ui, seed: &mut Option<u64>) {
+    let seed_number = seed.unwrap_or(rand::random());
+    ui.horizontal(|ui| {
+        ui.radio_value(seed, None, "Random");
+        ui.radio_value(seed, Some(seed_number), "Seeded");
+    });
+    if let Some(s) = seed {
+        ui.horizontal(|ui| {
+            ui.label("Seed");
+            DragValue::new(s).speed(10).ui(ui);
+            if ui.button("Random Seed").clicked() {
+                *s = rand::random();
+            }
+        });
+    }
+}
+
+/// Largest number of pixels a filter is allowed to produce, about 1 GiB of RGBA data.
+const MAX_PIXELS: u64 = 1 << 28;
+
+#[derive(Debug)]
+pub enum FilterError {
+    Image(ImageError),
+    InvalidSize {
+        width: u32,
+        height: u32,
+    },
+    InvalidQuantizationTable {
+        len: usize,
+    },
+    InvalidSigma(f32),
+    DegeneratePerspective,
+    /// The corrupted file couldn't be decoded after a few attempts
+    UndecodableBend {
+        attempts: usize,
+    },
+    Cancelled,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(e) => write!(f, "{e}"),
+            Self::InvalidSize { width, height } => {
+                write!(f, "invalid image size ({width} x {height})")
+            }
+            Self::InvalidQuantizationTable { len } => {
+                write!(f, "quantization tables need 64 values, got {len}")
+            }
+            Self::InvalidSigma(sigma) => write!(f, "blur sigma must be positive, got {sigma}"),
+            Self::DegeneratePerspective => {
+                write!(f, "the perspective corners don't form a quadrilateral")
+            }
+            Self::UndecodableBend { attempts } => write!(
+                f,
+                "the corrupted file couldn't be decoded after {attempts} attempts, try fewer corruptions"
+            ),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for FilterError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Image(e) => Some(e),
+            Self::InvalidSize { .. }
+            | Self::InvalidQuantizationTable { .. }
+            | Self::InvalidSigma(_)
+            | Self::DegeneratePerspective
+            | Self::UndecodableBend { .. }
+            | Self::Cancelled => None,
+        }
+    }
+}
+
+impl From<ImageError> for FilterError {
+    fn from(e: ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+/// How a filter treats the alpha channel of transparent images.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum AlphaMode {
+    /// The filter only changes colours and the original alpha is put back afterwards,
+    /// unless the filter moves pixels around, in which case alpha moves with them.
+    #[default]
+    Preserve,
+    /// The image is blended over an opaque background colour before filtering.
+    Composite { background: [u8; 3] },
+    /// The filter changes alpha like any other channel, e.g. `Invert` also inverts opacity
+    /// and `JpegCompression` compresses it as a separate grayscale image.
+    Process,
+}
+
+impl AlphaMode {
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Alpha");
+            ui.radio_value(self, Self::Preserve, "Preserve")
+                .on_hover_text("Keep the original transparency");
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Composite { .. }),
+                    "Composite",
+                ))
+                .on_hover_text("Fill transparent areas with a background colour")
+                .clicked()
+            {
+                *self = Self::Composite {
+                    background: [255, 255, 255],
+                };
+            }
+            ui.radio_value(self, Self::Process, "Process")
+                .on_hover_text("Apply the filter to transparency as well");
+            if let Self::Composite { background } = self {
+                ui.color_edit_button_srgb(background);
+            }
+        });
+    }
+}
+
+fn default_iterations() -> u32 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ResizeOption {
     Pixels(u32, u32),
     Percentage(f32, f32),
+    /// Largest size fitting in the box that keeps the aspect ratio
+    Fit(u32, u32),
+    /// Smallest size covering the box that keeps the aspect ratio, cropped to the box
+    Fill(u32, u32),
+    /// Scales the longest side of the image to the given length
+    LongestEdge(u32),
+}
+
+impl ResizeOption {
+    /// Size an image of `source_width` x `source_height` is resized to, before `Fill` crops it.
+    fn resized_size(&self, source_width: u32, source_height: u32) -> (u32, u32) {
+        let (source_width, source_height) = (source_width as f64, source_height as f64);
+        // Size of the image keeping its aspect ratio, scaled by `scale`. Thin images
+        // keep at least one pixel, unless the target size is zero.
+        let scaled = |scale: f64| {
+            let side = |v: f64| if v > 0. { v.round().max(1.) as u32 } else { 0 };
+            (side(source_width * scale), side(source_height * scale))
+        };
+        match self {
+            Self::Pixels(w, h) => (*w, *h),
+            Self::Percentage(w, h) => {
+                let w = (source_width as f32 * w) as u32;
+                let h = (source_height as f32 * h) as u32;
+                (w, h)
+            }
+            Self::Fit(w, h) => scaled((*w as f64 / source_width).min(*h as f64 / source_height)),
+            Self::Fill(w, h) if *w > 0 && *h > 0 => {
+                scaled((*w as f64 / source_width).max(*h as f64 / source_height))
+            }
+            Self::Fill(w, h) => (*w, *h),
+            Self::LongestEdge(length) => scaled(*length as f64 / source_width.max(source_height)),
+        }
+    }
+}
+
+/// Resampling algorithm used by the `Resize` filter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Sampling {
+    #[default]
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    Lanczos3,
+}
+
+impl Sampling {
+    const ALL: &[Self] = &[
+        Self::Nearest,
+        Self::Triangle,
+        Self::CatmullRom,
+        Self::Gaussian,
+        Self::Lanczos3,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Nearest => "Nearest",
+            Self::Triangle => "Triangle",
+            Self::CatmullRom => "Catmull-Rom",
+            Self::Gaussian => "Gaussian",
+            Self::Lanczos3 => "Lanczos 3",
+        }
+    }
+
+    fn filter_type(self) -> FilterType {
+        match self {
+            Self::Nearest => FilterType::Nearest,
+            Self::Triangle => FilterType::Triangle,
+            Self::CatmullRom => FilterType::CatmullRom,
+            Self::Gaussian => FilterType::Gaussian,
+            Self::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum ImageFilter {
-    JpegCompression { quality: u8 },
-    Brightness { percentage: u8 },
-    Sharpen { strength: u8 },
+    JpegCompression {
+        quality: u8,
+        /// Number of times the image is encoded and decoded
+        #[serde(default = "default_iterations")]
+        iterations: u32,
+        /// Largest random change of the quality on every generation
+        #[serde(default)]
+        quality_jitter: u8,
+        #[serde(default)]
+        drift: JpegDrift,
+        #[serde(default)]
+        seed: Option<u64>,
+        #[serde(default)]
+        subsampling: ChromaSubsampling,
+        #[serde(default)]
+        tables: QuantizationTables,
+    },
+    Brightness {
+        percentage: u8,
+    },
+    Sharpen {
+        strength: u8,
+    },
     BoxBlur,
-    GaussianBlur { sigma: f32 },
-    Saturate { percentage: u16 },
-    Noise { strength: u8, seed: Option<u64> },
-    Resize { size: ResizeOption },
+    GaussianBlur {
+        sigma: f32,
+    },
+    Saturate {
+        percentage: u16,
+    },
+    Noise {
+        strength: u8,
+        seed: Option<u64>,
+        #[serde(default)]
+        model: NoiseModel,
+    },
+    Resize {
+        size: ResizeOption,
+        #[serde(default)]
+        sampling: Sampling,
+    },
     Invert,
+    GifPalette {
+        colors: u16,
+        speed: u8,
+    },
+    Quantize {
+        colors: u16,
+        palette: PaletteSource,
+        dithering: Dithering,
+    },
+    Pixelate {
+        size: u32,
+        shape: CellShape,
+        averaging: Averaging,
+    },
+    Crop {
+        rect: CropRect,
+    },
+    Rotate {
+        /// Clockwise, in degrees
+        degrees: f32,
+        /// Whether the canvas grows to fit the rotated image
+        expand: bool,
+        fill: [u8; 4],
+    },
+    Flip {
+        horizontal: bool,
+        vertical: bool,
+    },
+    Perspective {
+        /// Where the top left, top right, bottom right and bottom left corners of the
+        /// image end up, as factors of the image size
+        corners: [[f32; 2]; 4],
+        fill: [u8; 4],
+    },
+    PixelSort {
+        direction: SortDirection,
+        /// Key deciding which pixels are sorted, those within `lower..=upper`
+        span_key: PixelKey,
+        lower: f32,
+        upper: f32,
+        sort_key: PixelKey,
+        reverse: bool,
+    },
+    DataBend {
+        format: BendFormat,
+        /// Number of random changes to the encoded bytes
+        corruptions: u32,
+        operations: BendOperations,
+        seed: Option<u64>,
+    },
+    ChannelShift {
+        mode: ShiftMode,
+        edges: EdgeMode,
+    },
+    ScanlineGlitch {
+        settings: ScanlineSettings,
+        seed: Option<u64>,
+    },
 }
 
 impl ImageFilter {
     pub const DEFAULTS: &[ImageFilter] = &[
-        Self::JpegCompression { quality: 80 },
+        Self::JpegCompression {
+            quality: 80,
+            iterations: 1,
+            quality_jitter: 0,
+            drift: JpegDrift::None,
+            seed: None,
+            subsampling: ChromaSubsampling::Full,
+            tables: QuantizationTables::Standard,
+        },
         Self::Brightness { percentage: 100 },
         Self::Sharpen { strength: 50 },
         Self::BoxBlur,
@@ -41,11 +545,84 @@ impl ImageFilter {
         Self::Noise {
             strength: 10,
             seed: None,
+            model: NoiseModel::Darken,
         },
         Self::Resize {
             size: ResizeOption::Percentage(1.0, 1.0),
+            sampling: Sampling::Nearest,
         },
         Self::Invert,
+        Self::GifPalette {
+            colors: 64,
+            speed: 10,
+        },
+        Self::Quantize {
+            colors: 16,
+            palette: PaletteSource::MedianCut,
+            dithering: Dithering::None,
+        },
+        Self::Pixelate {
+            size: 8,
+            shape: CellShape::Square,
+            averaging: Averaging::Mean,
+        },
+        Self::Crop {
+            rect: CropRect::Percentage {
+                x: 0.,
+                y: 0.,
+                width: 1.,
+                height: 1.,
+            },
+        },
+        Self::Rotate {
+            degrees: 0.,
+            expand: true,
+            fill: [0, 0, 0, 0],
+        },
+        Self::Flip {
+            horizontal: true,
+            vertical: false,
+        },
+        Self::Perspective {
+            corners: [[0., 0.], [1., 0.], [1., 1.], [0., 1.]],
+            fill: [0, 0, 0, 0],
+        },
+        Self::PixelSort {
+            direction: SortDirection::Rows,
+            span_key: PixelKey::Luminance,
+            lower: 0.25,
+            upper: 0.8,
+            sort_key: PixelKey::Luminance,
+            reverse: false,
+        },
+        Self::DataBend {
+            format: BendFormat::Jpeg { quality: 75 },
+            corruptions: 10,
+            operations: BendOperations {
+                flip: true,
+                shift: true,
+                repeat: true,
+                zero: true,
+            },
+            seed: None,
+        },
+        Self::ChannelShift {
+            mode: ShiftMode::Offset {
+                channels: [[4, 0], [0, 0], [-4, 0]],
+            },
+            edges: EdgeMode::Wrap,
+        },
+        Self::ScanlineGlitch {
+            settings: ScanlineSettings {
+                bands: 6,
+                displacement: 0.05,
+                scanlines: 0.25,
+                wobble: 2.,
+                bleed: 4,
+                tracking_bars: 1,
+            },
+            seed: None,
+        },
     ];
 
     pub const NAMES: &[&str] = &[
@@ -58,9 +635,20 @@ impl ImageFilter {
         "Noise",
         "Resize",
         "Invert",
+        "GIF Palette",
+        "Quantize",
+        "Pixelate",
+        "Crop",
+        "Rotate",
+        "Flip",
+        "Perspective",
+        "Pixel Sort",
+        "Data Bend",
+        "Channel Shift",
+        "Scanline Glitch",
     ];
 
-    pub fn name(&self) -> &str {
+    pub fn name(&self) -> &'static str {
         match self {
             Self::JpegCompression { .. } => Self::NAMES[0],
             Self::Brightness { .. } => Self::NAMES[1],
@@ -71,13 +659,140 @@ impl ImageFilter {
             Self::Noise { .. } => Self::NAMES[6],
             Self::Resize { .. } => Self::NAMES[7],
             Self::Invert => Self::NAMES[8],
+            Self::GifPalette { .. } => Self::NAMES[9],
+            Self::Quantize { .. } => Self::NAMES[10],
+            Self::Pixelate { .. } => Self::NAMES[11],
+            Self::Crop { .. } => Self::NAMES[12],
+            Self::Rotate { .. } => Self::NAMES[13],
+            Self::Flip { .. } => Self::NAMES[14],
+            Self::Perspective { .. } => Self::NAMES[15],
+            Self::PixelSort { .. } => Self::NAMES[16],
+            Self::DataBend { .. } => Self::NAMES[17],
+            Self::ChannelShift { .. } => Self::NAMES[18],
+            Self::ScanlineGlitch { .. } => Self::NAMES[19],
+        }
+    }
+
+    /// Whether applying the filter twice to the same image gives the same result.
+    pub fn is_deterministic(&self) -> bool {
+        match self {
+            Self::JpegCompression {
+                quality_jitter,
+                drift,
+                seed,
+                ..
+            } => seed.is_some() || (*quality_jitter == 0 && *drift != JpegDrift::Offset),
+            Self::Noise { seed, .. }
+            | Self::DataBend { seed, .. }
+            | Self::ScanlineGlitch { seed, .. } => seed.is_some(),
+            _ => true,
+        }
+    }
+
+    /// Scales the parameters given in pixels, for rendering on an image scaled by `factor`.
+    pub fn scale_pixels(&mut self, factor: f32) {
+        let scale = |v: &mut u32| *v = (*v as f32 * factor).round().max(1.) as u32;
+        match self {
+            Self::Pixelate { size, .. } => scale(size),
+            Self::Resize {
+                size:
+                    ResizeOption::Pixels(width, height)
+                    | ResizeOption::Fit(width, height)
+                    | ResizeOption::Fill(width, height),
+                ..
+            } => {
+                scale(width);
+                scale(height);
+            }
+            Self::Resize {
+                size: ResizeOption::LongestEdge(length),
+                ..
+            } => scale(length),
+            Self::Crop {
+                rect:
+                    CropRect::Pixels {
+                        x,
+                        y,
+                        width,
+                        height,
+                    },
+            } => {
+                *x = (*x as f32 * factor).round() as u32;
+                *y = (*y as f32 * factor).round() as u32;
+                scale(width);
+                scale(height);
+            }
+            Self::ChannelShift {
+                mode: ShiftMode::Offset { channels },
+                ..
+            } => {
+                for offset in channels.iter_mut().flatten() {
+                    *offset = (*offset as f32 * factor).round() as i32;
+                }
+            }
+            Self::ScanlineGlitch { settings, .. } => {
+                settings.wobble *= factor;
+                settings.bleed = (settings.bleed as f32 * factor).round() as u32;
+            }
+            _ => {}
         }
     }
 
+    /// Whether the filter works on fixed-size blocks or encoded bytes, which can't be scaled
+    /// along with a downscaled preview.
+    pub fn is_scale_dependent(&self) -> bool {
+        matches!(self, Self::JpegCompression { .. } | Self::DataBend { .. })
+    }
+
+    /// Whether the filter moves pixels rather than changing their colour.
+    fn is_geometric(&self) -> bool {
+        matches!(
+            self,
+            Self::Resize { .. }
+                | Self::Crop { .. }
+                | Self::Rotate { .. }
+                | Self::Flip { .. }
+                | Self::Perspective { .. }
+                | Self::PixelSort { .. }
+        )
+    }
+
     pub fn ui(&mut self, ui: &mut Ui) {
         match self {
-            Self::JpegCompression { quality } => {
-                Slider::new(quality, 1..=100).text("Quality").ui(ui);
+            Self::JpegCompression {
+                quality,
+                iterations,
+                quality_jitter,
+                drift,
+                seed,
+                subsampling,
+                tables,
+            } => {
+                ui.add_enabled(
+                    tables.uses_quality(),
+                    Slider::new(quality, 1..=100).text("Quality"),
+                )
+                .on_disabled_hover_text("Custom tables replace the quality setting");
+                subsampling.ui(ui);
+                tables.ui(ui, *quality);
+                Slider::new(iterations, 1..=200)
+                    .logarithmic(true)
+                    .text("Generations")
+                    .ui(ui);
+                ui.add_enabled(
+                    tables.uses_quality(),
+                    Slider::new(quality_jitter, 0..=50).text("Quality jitter"),
+                )
+                .on_disabled_hover_text("Custom tables replace the quality setting");
+                ui.horizontal(|ui| {
+                    ui.label("Between generations");
+                    ui.radio_value(drift, JpegDrift::None, "Nothing");
+                    ui.radio_value(drift, JpegDrift::Offset, "Offset");
+                    ui.radio_value(drift, JpegDrift::Resize, "Resize");
+                });
+                if *quality_jitter > 0 || *drift == JpegDrift::Offset {
+                    seed_ui(ui, seed);
+                }
             }
             Self::Brightness { percentage } => {
                 Slider::new(percentage, 0..=200)
@@ -95,46 +810,54 @@ impl ImageFilter {
                     .text("Saturation (%)")
                     .ui(ui);
             }
-            Self::Noise { strength, seed } => {
-                let seed_number = seed.unwrap_or(rand::random());
-                ui.horizontal(|ui| {
-                    ui.radio_value(seed, None, "Random");
-                    ui.radio_value(seed, Some(seed_number), "Seeded");
-                });
-                if let Some(s) = seed {
-                    ui.horizontal(|ui| {
-                        ui.label("Seed");
-                        DragValue::new(s).speed(10).ui(ui);
-                        if ui.button("Random Seed").clicked() {
-                            *s = rand::random();
-                        }
-                    });
-                }
+            Self::Noise {
+                strength,
+                seed,
+                model,
+            } => {
+                seed_ui(ui, seed);
+                model.ui(ui);
                 Slider::new(strength, 0..=100).text("Noise Strength").ui(ui);
             }
-            Self::Resize { size } => {
+            Self::Resize { size, sampling } => {
                 ui.horizontal(|ui| {
-                    if ui
-                        .add(RadioButton::new(
+                    let modes = [
+                        (
                             matches!(size, ResizeOption::Pixels(_, _)),
                             "Pixels",
-                        ))
-                        .clicked()
-                    {
-                        *size = ResizeOption::Pixels(128, 128);
-                    }
-                    if ui
-                        .add(RadioButton::new(
+                            ResizeOption::Pixels(128, 128),
+                        ),
+                        (
                             matches!(size, ResizeOption::Percentage(_, _)),
                             "Factor",
-                        ))
-                        .clicked()
-                    {
-                        *size = ResizeOption::Percentage(1.0, 1.0);
+                            ResizeOption::Percentage(1.0, 1.0),
+                        ),
+                        (
+                            matches!(size, ResizeOption::Fit(_, _)),
+                            "Fit",
+                            ResizeOption::Fit(128, 128),
+                        ),
+                        (
+                            matches!(size, ResizeOption::Fill(_, _)),
+                            "Fill",
+                            ResizeOption::Fill(128, 128),
+                        ),
+                        (
+                            matches!(size, ResizeOption::LongestEdge(_)),
+                            "Longest edge",
+                            ResizeOption::LongestEdge(128),
+                        ),
+                    ];
+                    for (selected, label, default) in modes {
+                        if ui.add(RadioButton::new(selected, label)).clicked() {
+                            *size = default;
+                        }
                     }
                 });
                 match size {
-                    ResizeOption::Pixels(width, height) => {
+                    ResizeOption::Pixels(width, height)
+                    | ResizeOption::Fit(width, height)
+                    | ResizeOption::Fill(width, height) => {
                         ui.horizontal(|ui| {
                             DragValue::new(width).suffix("px").ui(ui);
                             ui.label("Width");
@@ -154,34 +877,181 @@ impl ImageFilter {
                             ui.label("Height factor");
                         });
                     }
+                    ResizeOption::LongestEdge(length) => {
+                        ui.horizontal(|ui| {
+                            DragValue::new(length).suffix("px").ui(ui);
+                            ui.label("Length");
+                        });
+                    }
                 }
+                ComboBox::from_label("Sampling")
+                    .selected_text(sampling.name())
+                    .show_ui(ui, |ui| {
+                        for s in Sampling::ALL {
+                            ui.selectable_value(sampling, *s, s.name());
+                        }
+                    });
+            }
+            Self::GifPalette { colors, speed } => {
+                Slider::new(colors, 2..=256)
+                    .logarithmic(true)
+                    .text("Colours")
+                    .ui(ui);
+                Slider::new(speed, 1..=30)
+                    .text("Speed")
+                    .ui(ui)
+                    .on_hover_text("Lower values take longer but find a better palette");
+            }
+            Self::Quantize {
+                colors,
+                palette,
+                dithering,
+            } => quantize::ui(ui, colors, palette, dithering),
+            Self::Pixelate {
+                size,
+                shape,
+                averaging,
+            } => pixelate::ui(ui, size, shape, averaging),
+            Self::Crop { rect } => rect.ui(ui),
+            Self::Rotate {
+                degrees,
+                expand,
+                fill,
+            } => {
+                Slider::new(degrees, -180.0..=180.0)
+                    .text("Angle (°)")
+                    .ui(ui);
+                ui.checkbox(expand, "Grow canvas to fit");
+                ui.horizontal(|ui| {
+                    ui.color_edit_button_srgba_unmultiplied(fill);
+                    ui.label("Fill").on_hover_text(
+                        "Images without transparency are filled with the opaque colour",
+                    );
+                });
+            }
+            Self::Flip {
+                horizontal,
+                vertical,
+            } => {
+                ui.horizontal(|ui| {
+                    ui.checkbox(horizontal, "Horizontal");
+                    ui.checkbox(vertical, "Vertical");
+                });
+            }
+            Self::Perspective { corners, fill } => geometry::perspective_ui(ui, corners, fill),
+            Self::PixelSort {
+                direction,
+                span_key,
+                lower,
+                upper,
+                sort_key,
+                reverse,
+            } => pixel_sort::ui(ui, direction, span_key, lower, upper, sort_key, reverse),
+            Self::DataBend {
+                format,
+                corruptions,
+                operations,
+                seed,
+            } => {
+                databend::ui(ui, format, corruptions, operations);
+                seed_ui(ui, seed);
+            }
+            Self::ChannelShift { mode, edges } => channel_shift::ui(ui, mode, edges),
+            Self::ScanlineGlitch { settings, seed } => {
+                seed_ui(ui, seed);
+                settings.ui(ui);
             }
             Self::BoxBlur | Self::Invert => {}
         }
     }
 
-    // TODO: error management
-    pub fn apply(&self, img: DynamicImage) -> DynamicImage {
-        match self {
-            Self::JpegCompression { quality } => {
-                let mut buf = BufWriter::new(Vec::new());
-                let encoder = JpegEncoder::new_with_quality(&mut buf, *quality);
-                img.to_rgb8().write_with_encoder(encoder).unwrap();
-                let bytes = buf.into_inner().unwrap();
-                let decoder = JpegDecoder::new(Cursor::new(bytes)).unwrap();
-                DynamicImage::from_decoder(decoder).unwrap()
+    pub fn apply(
+        &self,
+        img: DynamicImage,
+        alpha: AlphaMode,
+        cancel: &CancelToken,
+    ) -> Result<DynamicImage, FilterError> {
+        if !img.color().has_alpha() {
+            return self.filter(img, false, cancel);
+        }
+        match alpha {
+            AlphaMode::Preserve if self.is_geometric() => self.filter(img, true, cancel),
+            AlphaMode::Preserve => {
+                let original = img.clone();
+                let img = self.filter(img, false, cancel)?;
+                Ok(restore_alpha(img, &original))
+            }
+            AlphaMode::Composite { background } => {
+                self.filter(composite(img, background), false, cancel)
+            }
+            AlphaMode::Process => self.filter(img, true, cancel),
+        }
+    }
+
+    fn filter(
+        &self,
+        img: DynamicImage,
+        process_alpha: bool,
+        cancel: &CancelToken,
+    ) -> Result<DynamicImage, FilterError> {
+        // Number of channels changed by filters working on single channels
+        let changed = |channels: usize| if process_alpha { channels } else { 3 };
+        let img = match self {
+            Self::JpegCompression {
+                quality,
+                iterations,
+                quality_jitter,
+                drift,
+                seed,
+                subsampling,
+                tables,
+            } => {
+                let mut random = ChaCha20Rng::seed_from_u64(seed.unwrap_or(rand::random()));
+                let mut out: DynamicImage = img.to_rgb8().into();
+                let mut alpha: Option<DynamicImage> = (process_alpha && img.color().has_alpha())
+                    .then(|| {
+                        let alpha = img.to_rgba8().pixels().map(|px| px[3]).collect();
+                        GrayImage::from_raw(img.width(), img.height(), alpha)
+                            .expect("alpha buffer should match the image size")
+                            .into()
+                    });
+                let iterations = (*iterations).max(1);
+                for i in 0..iterations {
+                    cancel.check()?;
+                    cancel.set_step_progress(i as f32 / iterations as f32);
+                    let jitter = *quality_jitter as i32;
+                    let quality = *quality as i32 + random.random_range(-jitter..=jitter);
+                    let settings = JpegSettings {
+                        quality: quality.clamp(1, 100) as u8,
+                        subsampling: *subsampling,
+                        tables,
+                    };
+                    let offset = (random.random_range(1..8), random.random_range(1..8));
+                    out = jpeg::generation(out, settings, *drift, offset)?;
+                    if let Some(a) = alpha {
+                        alpha = Some(jpeg::generation(a, settings, *drift, offset)?);
+                    }
+                }
+                if let Some(alpha) = alpha {
+                    let alpha = alpha.into_luma8();
+                    let mut rgba = out.into_rgba8();
+                    for (px, a) in rgba.pixels_mut().zip(alpha.pixels()) {
+                        px[3] = a[0];
+                    }
+                    out = rgba.into();
+                }
+                // JPEG is 8-bit only, but the result keeps the pixel format of the source
+                convert_to(out, img.color())
             }
             Self::Brightness { percentage } => {
-                let mut img = img;
                 let percent = *percentage as f32 / 100.;
-                for (x, y, col) in img.clone().pixels() {
-                    let [r, g, b, a] = col.0;
-                    let r = (r as f32 * percent).clamp(0., 255.) as u8;
-                    let g = (g as f32 * percent).clamp(0., 255.) as u8;
-                    let b = (b as f32 * percent).clamp(0., 255.) as u8;
-                    img.put_pixel(x, y, Rgba([r, g, b, a]));
-                }
-                img
+                map_rows(img, cancel, |_, row, channels, max| {
+                    for px in row.chunks_exact_mut(channels) {
+                        for c in &mut px[..changed(channels)] {
+                            *c = (*c * percent).clamp(0., max);
+                        }
+                    }
+                })?
             }
             Self::Sharpen { strength } => {
                 let strength = *strength as f32 / 100.;
@@ -193,52 +1063,407 @@ impl ImageFilter {
                 let n = 1. / 9.;
                 img.filter3x3(&[n, n, n, n, n, n, n, n, n])
             }
-            Self::GaussianBlur { sigma } => img.blur(*sigma),
+            Self::GaussianBlur { sigma } => {
+                let mut rgba = img.to_rgba32f();
+                blur::gaussian(&mut rgba, *sigma, cancel)?;
+                convert_to(rgba.into(), img.color())
+            }
             Self::Saturate { percentage } => {
-                let mut img = img;
                 let percent = *percentage as f32 / 100.;
-                for (x, y, col) in img.clone().pixels() {
-                    let [r, g, b, a] = col.0;
-                    let (h, s, v) =
-                        hsv_from_rgb([r as f32 / 255., g as f32 / 255., b as f32 / 255.]);
-                    let s = (s * percent).clamp(0.0, 1.0);
-                    let [r, g, b] = rgb_from_hsv((h, s, v));
-                    let r = (r * 255.) as u8;
-                    let g = (g * 255.) as u8;
-                    let b = (b * 255.) as u8;
-                    img.put_pixel(x, y, Rgba([r, g, b, a]));
-                }
-                img
+                map_rows(img, cancel, |_, row, channels, max| {
+                    for px in row.chunks_exact_mut(channels) {
+                        let (h, s, v) = hsv_from_rgb([px[0] / max, px[1] / max, px[2] / max]);
+                        let s = (s * percent).clamp(0.0, 1.0);
+                        let [r, g, b] = rgb_from_hsv((h, s, v));
+                        px[0] = r * max;
+                        px[1] = g * max;
+                        px[2] = b * max;
+                    }
+                })?
             }
-            Self::Noise { strength, seed } => {
-                let mut img = img;
-                let mut random =
-                    rand_chacha::ChaCha20Rng::seed_from_u64(seed.unwrap_or(rand::random()));
+            Self::Noise {
+                strength,
+                seed,
+                model,
+            } => {
+                let seed = seed.unwrap_or(rand::random());
                 let percent = *strength as f32 / 100.;
-                for (x, y, col) in img.clone().pixels() {
-                    let rnoise = random.random_range(0.0..=1.0);
-                    let noise = 1.0 - (rnoise * percent);
-                    let col = col.map_without_alpha(|c| (((c as f32 / 255.) * noise) * 255.) as u8);
-                    img.put_pixel(x, y, col);
-                }
-                img
+                let noise = NoiseGenerator::new(*model, percent, seed, img.width(), img.height());
+                map_rows(img, cancel, |y, row, channels, max| {
+                    noise.row(y, row, channels, changed(channels), max);
+                })?
             }
-            Self::Resize { size } => {
-                let (width, height) = match size {
-                    ResizeOption::Pixels(w, h) => (*w, *h),
-                    ResizeOption::Percentage(w, h) => {
-                        let w = (img.width() as f32 * w) as u32;
-                        let h = (img.height() as f32 * h) as u32;
-                        (w, h)
+            Self::Resize { size, sampling } => {
+                let (width, height) = size.resized_size(img.width(), img.height());
+                if width == 0 || height == 0 || width as u64 * height as u64 > MAX_PIXELS {
+                    return Err(FilterError::InvalidSize { width, height });
+                }
+                let img = img.resize_exact(width, height, sampling.filter_type());
+                match size {
+                    ResizeOption::Fill(w, h) => {
+                        let (w, h) = ((*w).min(width), (*h).min(height));
+                        img.crop_imm((width - w) / 2, (height - h) / 2, w, h)
                     }
-                };
-                img.resize_exact(width, height, image::imageops::FilterType::Nearest)
+                    _ => img,
+                }
             }
             Self::Invert => {
                 let mut img = img;
                 img.invert();
+                if process_alpha {
+                    invert_alpha(&mut img);
+                }
                 img
             }
+            Self::GifPalette { colors, speed } => {
+                // GIF palettes are 8-bit, so only the pixel format is restored
+                let out = gif::round_trip(&img, *colors, *speed, process_alpha)?;
+                convert_to(out, img.color())
+            }
+            Self::Quantize {
+                colors,
+                palette,
+                dithering,
+            } => {
+                // The palette is picked from 8-bit colours, but pixels keep their precision
+                // until they are replaced
+                let palette = quantize::palette(&img.to_rgba8(), *palette, *colors);
+                let mut rgba = img.to_rgba32f();
+                quantize::apply(&mut rgba, &palette, *dithering, cancel)?;
+                convert_to(rgba.into(), img.color())
+            }
+            Self::Pixelate {
+                size,
+                shape,
+                averaging,
+            } => {
+                let mut rgba = img.to_rgba32f();
+                pixelate::pixelate(&mut rgba, *size, *shape, *averaging, cancel)?;
+                convert_to(rgba.into(), img.color())
+            }
+            Self::Crop { rect } => {
+                let (x, y, width, height) = rect.bounds(img.width(), img.height());
+                if width == 0 || height == 0 {
+                    return Err(FilterError::InvalidSize { width, height });
+                }
+                img.crop_imm(x, y, width, height)
+            }
+            Self::Rotate {
+                degrees,
+                expand,
+                fill,
+            } => {
+                let turn = degrees.rem_euclid(360.);
+                // Quarter turns are done exactly rather than resampled
+                if turn == 0. {
+                    img
+                } else if turn == 180. {
+                    img.rotate180()
+                } else if turn == 90. && *expand {
+                    img.rotate90()
+                } else if turn == 270. && *expand {
+                    img.rotate270()
+                } else {
+                    let (width, height) =
+                        geometry::rotated_size(img.width(), img.height(), *degrees, *expand);
+                    if width as u64 * height as u64 > MAX_PIXELS {
+                        return Err(FilterError::InvalidSize { width, height });
+                    }
+                    let fill = fill_color(*fill, img.color());
+                    let out = geometry::rotate(&img.to_rgba32f(), *degrees, *expand, fill, cancel)?;
+                    convert_to(out.into(), img.color())
+                }
+            }
+            Self::Flip {
+                horizontal,
+                vertical,
+            } => {
+                let img = if *horizontal { img.fliph() } else { img };
+                if *vertical { img.flipv() } else { img }
+            }
+            Self::Perspective { corners, fill } => {
+                let fill = fill_color(*fill, img.color());
+                let out = geometry::perspective(&img.to_rgba32f(), corners, fill, cancel)?;
+                convert_to(out.into(), img.color())
+            }
+            Self::PixelSort {
+                direction,
+                span_key,
+                lower,
+                upper,
+                sort_key,
+                reverse,
+            } => {
+                let mut rgba = img.to_rgba32f();
+                pixel_sort::sort(
+                    &mut rgba,
+                    *direction,
+                    *span_key,
+                    (*lower, *upper),
+                    *sort_key,
+                    *reverse,
+                    cancel,
+                )?;
+                convert_to(rgba.into(), img.color())
+            }
+            Self::DataBend {
+                format,
+                corruptions,
+                operations,
+                seed,
+            } => {
+                let mut random = ChaCha20Rng::seed_from_u64(seed.unwrap_or(rand::random()));
+                let out = databend::bend(
+                    &img,
+                    *format,
+                    *corruptions,
+                    *operations,
+                    &mut random,
+                    process_alpha,
+                )?;
+                convert_to(out, img.color())
+            }
+            Self::ChannelShift { mode, edges } => {
+                let mut rgba = img.to_rgba32f();
+                channel_shift::shift(&mut rgba, *mode, *edges, cancel)?;
+                convert_to(rgba.into(), img.color())
+            }
+            Self::ScanlineGlitch { settings, seed } => {
+                let mut random = ChaCha20Rng::seed_from_u64(seed.unwrap_or(rand::random()));
+                let mut rgba = img.to_rgba32f();
+                scanline::glitch(&mut rgba, *settings, &mut random, process_alpha, cancel)?;
+                convert_to(rgba.into(), img.color())
+            }
+        };
+        Ok(img)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{Rgba, RgbaImage};
+
+    use super::*;
+
+    fn gradient() -> DynamicImage {
+        RgbaImage::from_fn(97, 61, |x, y| {
+            Rgba([
+                (x * 2) as u8,
+                (y * 4) as u8,
+                ((x * y) % 256) as u8,
+                (255 - x) as u8,
+            ])
+        })
+        .into()
+    }
+
+    fn render_with_threads(filter: &ImageFilter, threads: usize) -> DynamicImage {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+            .install(|| {
+                filter
+                    .apply(gradient(), AlphaMode::Process, &CancelToken::default())
+                    .unwrap()
+            })
+    }
+
+    fn assert_thread_independent(filter: ImageFilter) {
+        let single = render_with_threads(&filter, 1);
+        let multi = render_with_threads(&filter, 8);
+        assert_eq!(single.as_bytes(), multi.as_bytes(), "{filter:?}");
+    }
+
+    #[test]
+    fn seeded_noise_does_not_depend_on_threads() {
+        for model in [
+            NoiseModel::Darken,
+            NoiseModel::Gaussian,
+            NoiseModel::SaltAndPepper,
+            NoiseModel::Poisson,
+            NoiseModel::Color,
+            NoiseModel::FilmGrain { size: 3. },
+        ] {
+            assert_thread_independent(ImageFilter::Noise {
+                strength: 40,
+                seed: Some(42),
+                model,
+            });
+        }
+    }
+
+    #[test]
+    fn gaussian_blur_does_not_depend_on_threads() {
+        assert_thread_independent(ImageFilter::GaussianBlur { sigma: 2.5 });
+    }
+
+    #[test]
+    fn pixel_sort_does_not_depend_on_threads() {
+        for direction in [
+            SortDirection::Rows,
+            SortDirection::Columns,
+            SortDirection::Angle(30.),
+        ] {
+            assert_thread_independent(ImageFilter::PixelSort {
+                direction,
+                span_key: PixelKey::Luminance,
+                lower: 0.2,
+                upper: 0.8,
+                sort_key: PixelKey::Hue,
+                reverse: false,
+            });
+        }
+    }
+
+    #[test]
+    fn pixel_sort_thresholds_can_be_swapped() {
+        let sort = |lower, upper| {
+            ImageFilter::PixelSort {
+                direction: SortDirection::Rows,
+                span_key: PixelKey::Luminance,
+                lower,
+                upper,
+                sort_key: PixelKey::Hue,
+                reverse: false,
+            }
+            .apply(gradient(), AlphaMode::Process, &CancelToken::default())
+            .unwrap()
+        };
+        let sorted = sort(0.2, 0.8);
+        assert_ne!(sorted, gradient());
+        assert_eq!(sort(0.8, 0.2), sorted);
+    }
+
+    #[test]
+    fn pixel_parameters_scale_with_the_image() {
+        let mut pixelate = ImageFilter::Pixelate {
+            size: 16,
+            shape: CellShape::Square,
+            averaging: Averaging::Mean,
+        };
+        pixelate.scale_pixels(0.25);
+        assert!(matches!(pixelate, ImageFilter::Pixelate { size: 4, .. }));
+        // Cells never get smaller than a pixel
+        pixelate.scale_pixels(0.1);
+        assert!(matches!(pixelate, ImageFilter::Pixelate { size: 1, .. }));
+
+        let mut shift = ImageFilter::ChannelShift {
+            mode: ShiftMode::Offset {
+                channels: [[8, -4], [0, 0], [-10, 2]],
+            },
+            edges: EdgeMode::Wrap,
+        };
+        shift.scale_pixels(0.5);
+        assert_eq!(
+            shift,
+            ImageFilter::ChannelShift {
+                mode: ShiftMode::Offset {
+                    channels: [[4, -2], [0, 0], [-5, 1]],
+                },
+                edges: EdgeMode::Wrap,
+            }
+        );
+
+        let mut resize = ImageFilter::Resize {
+            size: ResizeOption::Percentage(0.5, 0.5),
+            sampling: Sampling::default(),
+        };
+        let before = resize.clone();
+        resize.scale_pixels(0.5);
+        assert_eq!(resize, before);
+    }
+
+    #[test]
+    fn resize_keeps_the_aspect_ratio() {
+        assert_eq!(
+            ResizeOption::Fit(100, 100).resized_size(400, 200),
+            (100, 50)
+        );
+        assert_eq!(ResizeOption::Fit(100, 10).resized_size(400, 200), (20, 10));
+        assert_eq!(
+            ResizeOption::Fill(100, 100).resized_size(400, 200),
+            (200, 100)
+        );
+        assert_eq!(ResizeOption::LongestEdge(50).resized_size(97, 61), (50, 31));
+        assert_eq!(ResizeOption::LongestEdge(50).resized_size(61, 97), (31, 50));
+        // Thin images keep a pixel
+        assert_eq!(ResizeOption::Fit(10, 10).resized_size(1000, 1), (10, 1));
+        assert_eq!(
+            ResizeOption::Percentage(0.5, 0.5).resized_size(97, 61),
+            (48, 30)
+        );
+
+        // Fill crops the overflowing side to the box
+        let filled = ImageFilter::Resize {
+            size: ResizeOption::Fill(40, 40),
+            sampling: Sampling::default(),
         }
+        .apply(gradient(), AlphaMode::Process, &CancelToken::default())
+        .unwrap();
+        assert_eq!((filled.width(), filled.height()), (40, 40));
+    }
+
+    #[test]
+    fn quarter_turns_move_pixels_exactly() {
+        let rotate = |degrees, expand| {
+            ImageFilter::Rotate {
+                degrees,
+                expand,
+                fill: [0; 4],
+            }
+            .apply(gradient(), AlphaMode::Process, &CancelToken::default())
+            .unwrap()
+            .into_rgba8()
+        };
+        let img = gradient().into_rgba8();
+        let (width, height) = img.dimensions();
+
+        let turned = rotate(90., true);
+        assert_eq!(turned.dimensions(), (height, width));
+        assert_eq!(turned.get_pixel(0, 0), img.get_pixel(0, height - 1));
+        assert_eq!(turned.get_pixel(height - 1, 0), img.get_pixel(0, 0));
+
+        let turned = rotate(-180., false);
+        assert_eq!(turned.dimensions(), (width, height));
+        assert_eq!(turned.get_pixel(0, 0), img.get_pixel(width - 1, height - 1));
+        assert_eq!(turned.get_pixel(3, 5), img.get_pixel(width - 4, height - 6));
+    }
+
+    #[test]
+    fn geometric_filters_keep_the_pixel_format() {
+        let gray = DynamicImage::from(gradient().into_luma8());
+        for filter in [
+            ImageFilter::Rotate {
+                degrees: 30.,
+                expand: true,
+                fill: [0; 4],
+            },
+            ImageFilter::Perspective {
+                corners: [[0.1, 0.], [1., 0.2], [0.9, 1.], [0., 0.8]],
+                fill: [0; 4],
+            },
+        ] {
+            let out = filter
+                .apply(gray.clone(), AlphaMode::Process, &CancelToken::default())
+                .unwrap();
+            assert_eq!(out.color(), ColorType::L8);
+        }
+    }
+
+    #[test]
+    fn out_of_range_crops_fail() {
+        let crop = ImageFilter::Crop {
+            rect: CropRect::Pixels {
+                x: 200,
+                y: 0,
+                width: 10,
+                height: 10,
+            },
+        };
+        assert!(matches!(
+            crop.apply(gradient(), AlphaMode::Process, &CancelToken::default()),
+            Err(FilterError::InvalidSize { width: 0, .. })
+        ));
     }
 }
@@ -0,0 +1,371 @@
+use eframe::egui::{DragValue, RadioButton, Ui, Widget};
+use image::{Rgba, Rgba32FImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+/// Rectangle kept by the `Crop` filter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum CropRect {
+    Pixels {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Position and size as factors of the image size
+    Percentage {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+}
+
+impl CropRect {
+    /// Position and size of the rectangle in an image of the given size, clamped to the image.
+    pub fn bounds(&self, image_width: u32, image_height: u32) -> (u32, u32, u32, u32) {
+        let (x, y, width, height) = match *self {
+            Self::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => (x, y, width, height),
+            Self::Percentage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let scale = |v: f32, size: u32| (v.clamp(0., 1.) * size as f32).round() as u32;
+                (
+                    scale(x, image_width),
+                    scale(y, image_height),
+                    scale(width, image_width),
+                    scale(height, image_height),
+                )
+            }
+        };
+        let (x, y) = (x.min(image_width), y.min(image_height));
+        (
+            x,
+            y,
+            width.min(image_width - x),
+            height.min(image_height - y),
+        )
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Pixels { .. }),
+                    "Pixels",
+                ))
+                .clicked()
+            {
+                *self = Self::Pixels {
+                    x: 0,
+                    y: 0,
+                    width: 128,
+                    height: 128,
+                };
+            }
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Percentage { .. }),
+                    "Factor",
+                ))
+                .clicked()
+            {
+                *self = Self::Percentage {
+                    x: 0.,
+                    y: 0.,
+                    width: 1.,
+                    height: 1.,
+                };
+            }
+        });
+        match self {
+            Self::Pixels {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                ui.horizontal(|ui| {
+                    DragValue::new(x).suffix("px").ui(ui);
+                    DragValue::new(y).suffix("px").ui(ui);
+                    ui.label("Position");
+                });
+                ui.horizontal(|ui| {
+                    DragValue::new(width).suffix("px").ui(ui);
+                    DragValue::new(height).suffix("px").ui(ui);
+                    ui.label("Size");
+                });
+            }
+            Self::Percentage {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                ui.horizontal(|ui| {
+                    DragValue::new(x).speed(0.01).range(0.0..=1.0).ui(ui);
+                    DragValue::new(y).speed(0.01).range(0.0..=1.0).ui(ui);
+                    ui.label("Position factor");
+                });
+                ui.horizontal(|ui| {
+                    DragValue::new(width).speed(0.01).range(0.0..=1.0).ui(ui);
+                    DragValue::new(height).speed(0.01).range(0.0..=1.0).ui(ui);
+                    ui.label("Size factor");
+                });
+            }
+        }
+    }
+}
+
+/// Settings UI of the `Perspective` filter.
+pub fn perspective_ui(ui: &mut Ui, corners: &mut [[f32; 2]; 4], fill: &mut [u8; 4]) {
+    let names = ["Top left", "Top right", "Bottom right", "Bottom left"];
+    for (corner, name) in corners.iter_mut().zip(names) {
+        ui.horizontal(|ui| {
+            for v in corner {
+                DragValue::new(v).speed(0.005).range(-1.0..=2.0).ui(ui);
+            }
+            ui.label(name);
+        });
+    }
+    ui.horizontal(|ui| {
+        ui.color_edit_button_srgba_unmultiplied(fill);
+        ui.label("Fill")
+            .on_hover_text("Images without transparency are filled with the opaque colour");
+    });
+}
+
+/// Bilinear sample of the image at `(x, y)`, in pixels from the centre of the top-left
+/// pixel. Pixels outside the image take the fill colour, which also smooths the edges.
+fn sample(img: &Rgba32FImage, x: f32, y: f32, fill: [f32; 4]) -> [f32; 4] {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |x: f32, y: f32| {
+        if x < 0. || y < 0. || x >= img.width() as f32 || y >= img.height() as f32 {
+            fill
+        } else {
+            img.get_pixel(x as u32, y as u32).0
+        }
+    };
+    // Interpolate with premultiplied alpha, so transparent pixels don't bleed their colour
+    let mut sum = [0.; 4];
+    for (dx, dy, weight) in [
+        (0., 0., (1. - fx) * (1. - fy)),
+        (1., 0., fx * (1. - fy)),
+        (0., 1., (1. - fx) * fy),
+        (1., 1., fx * fy),
+    ] {
+        if weight == 0. {
+            continue;
+        }
+        let px = pixel(x0 + dx, y0 + dy);
+        for c in 0..3 {
+            sum[c] += px[c] * px[3] * weight;
+        }
+        sum[3] += px[3] * weight;
+    }
+    let alpha = sum[3];
+    if alpha > 0. {
+        for c in &mut sum[..3] {
+            *c /= alpha;
+        }
+    }
+    sum
+}
+
+/// Builds a `width` x `height` image, `source` giving the position in `img` of every output
+/// pixel centre, or `None` for pixels only showing the fill colour.
+fn warp(
+    img: &Rgba32FImage,
+    width: u32,
+    height: u32,
+    fill: [f32; 4],
+    cancel: &CancelToken,
+    source: impl Fn(f32, f32) -> Option<(f32, f32)> + Sync,
+) -> Result<Rgba32FImage, FilterError> {
+    let mut out = Rgba32FImage::from_pixel(width, height, Rgba(fill));
+    if width == 0 {
+        return Ok(out);
+    }
+    out.par_chunks_mut(width as usize * 4)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            cancel.check()?;
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                if let Some((sx, sy)) = source(x as f32 + 0.5, y as f32 + 0.5) {
+                    px.copy_from_slice(&sample(img, sx - 0.5, sy - 0.5, fill));
+                }
+            }
+            Ok::<_, FilterError>(())
+        })?;
+    Ok(out)
+}
+
+/// Size of an image of `width` x `height` after [`rotate`].
+pub fn rotated_size(width: u32, height: u32, degrees: f32, expand: bool) -> (u32, u32) {
+    if !expand {
+        return (width, height);
+    }
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (w, h) = (width as f32, height as f32);
+    // Ignore rounding errors, or a quarter turn would add a row of fill
+    let fit = |v: f32| (v - 1e-3).ceil().max(1.) as u32;
+    (
+        fit(w * cos.abs() + h * sin.abs()),
+        fit(w * sin.abs() + h * cos.abs()),
+    )
+}
+
+/// Rotates the image clockwise by `degrees` around its centre. With `expand` the canvas grows
+/// to fit the whole rotated image, otherwise it keeps its size and the corners are cut off.
+pub fn rotate(
+    img: &Rgba32FImage,
+    degrees: f32,
+    expand: bool,
+    fill: [f32; 4],
+    cancel: &CancelToken,
+) -> Result<Rgba32FImage, FilterError> {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    let (width, height) = rotated_size(img.width(), img.height(), degrees, expand);
+    let (cx, cy) = (width as f32 / 2., height as f32 / 2.);
+    warp(img, width, height, fill, cancel, |x, y| {
+        let (dx, dy) = (x - cx, y - cy);
+        Some((cos * dx + sin * dy + w / 2., -sin * dx + cos * dy + h / 2.))
+    })
+}
+
+/// Moves the corners of the image to `corners`, given as factors of the image size in
+/// the order top left, top right, bottom right, bottom left.
+pub fn perspective(
+    img: &Rgba32FImage,
+    corners: &[[f32; 2]; 4],
+    fill: [f32; 4],
+    cancel: &CancelToken,
+) -> Result<Rgba32FImage, FilterError> {
+    let inverse = square_to_quad(corners)
+        .and_then(invert)
+        .ok_or(FilterError::DegeneratePerspective)?;
+    let (w, h) = (img.width() as f32, img.height() as f32);
+    warp(img, img.width(), img.height(), fill, cancel, |x, y| {
+        let [[a, b, c], [d, e, f], [g, h_, i]] = inverse;
+        let (x, y) = ((x / w) as f64, (y / h) as f64);
+        // `z` is the inverse of the projective coordinate of the matching point of the image,
+        // which is negative behind the horizon where the image would show up mirrored
+        let z = g * x + h_ * y + i;
+        if z <= 1e-12 {
+            return None;
+        }
+        let u = (a * x + b * y + c) / z;
+        let v = (d * x + e * y + f) / z;
+        Some((u as f32 * w, v as f32 * h))
+    })
+}
+
+/// Projective transform of the unit square to the quadrilateral, after Heckbert's
+/// "Fundamentals of Texture Mapping and Image Warping".
+fn square_to_quad(corners: &[[f32; 2]; 4]) -> Option<[[f64; 3]; 3]> {
+    let [[x0, y0], [x1, y1], [x2, y2], [x3, y3]] = corners.map(|c| c.map(|v| v as f64));
+    let (sx, sy) = (x0 - x1 + x2 - x3, y0 - y1 + y2 - y3);
+    let (g, h) = if sx.abs() < 1e-12 && sy.abs() < 1e-12 {
+        (0., 0.)
+    } else {
+        let (dx1, dx2, dy1, dy2) = (x1 - x2, x3 - x2, y1 - y2, y3 - y2);
+        let den = dx1 * dy2 - dx2 * dy1;
+        if den.abs() < 1e-12 {
+            return None;
+        }
+        ((sx * dy2 - dx2 * sy) / den, (dx1 * sy - sx * dy1) / den)
+    };
+    Some([
+        [x1 - x0 + g * x1, x3 - x0 + h * x3, x0],
+        [y1 - y0 + g * y1, y3 - y0 + h * y3, y0],
+        [g, h, 1.],
+    ])
+}
+
+fn invert(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let [[a, b, c], [d, e, f], [g, h, i]] = m;
+    let det = a * (e * i - f * h) - b * (d * i - f * g) + c * (d * h - e * g);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    Some([
+        [
+            (e * i - f * h) / det,
+            (c * h - b * i) / det,
+            (b * f - c * e) / det,
+        ],
+        [
+            (f * g - d * i) / det,
+            (a * i - c * g) / det,
+            (c * d - a * f) / det,
+        ],
+        [
+            (d * h - e * g) / det,
+            (b * g - a * h) / det,
+            (a * e - b * d) / det,
+        ],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crop_bounds() {
+        let pixels = CropRect::Pixels {
+            x: 10,
+            y: 20,
+            width: 30,
+            height: 40,
+        };
+        assert_eq!(pixels.bounds(100, 100), (10, 20, 30, 40));
+        // Clamped to the image
+        assert_eq!(pixels.bounds(25, 50), (10, 20, 15, 30));
+        // Out of range rectangles end up empty
+        assert_eq!(pixels.bounds(5, 5), (5, 5, 0, 0));
+
+        let percentage = CropRect::Percentage {
+            x: 0.25,
+            y: 0.5,
+            width: 0.5,
+            height: 2.,
+        };
+        assert_eq!(percentage.bounds(200, 100), (50, 50, 100, 50));
+    }
+
+    #[test]
+    fn identity_quad() {
+        let identity = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+        let corners = [[0., 0.], [1., 0.], [1., 1.], [0., 1.]];
+        assert_eq!(square_to_quad(&corners), Some(identity));
+        assert_eq!(invert(identity), Some(identity));
+
+        // A quad with all corners on a line has no inverse
+        let line = [[0., 0.], [0.5, 0.], [1., 0.], [0.25, 0.]];
+        assert!(square_to_quad(&line).and_then(invert).is_none());
+    }
+
+    #[test]
+    fn identity_perspective_keeps_the_image() {
+        let img =
+            Rgba32FImage::from_fn(13, 7, |x, y| Rgba([x as f32 / 13., y as f32 / 7., 0.5, 1.]));
+        let corners = [[0., 0.], [1., 0.], [1., 1.], [0., 1.]];
+        let out = perspective(&img, &corners, [0.; 4], &CancelToken::default()).unwrap();
+        for (a, b) in img.pixels().zip(out.pixels()) {
+            assert!(a.0.iter().zip(b.0).all(|(a, b)| (a - b).abs() < 1e-4));
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::io::Cursor;
+
+use color_quant::NeuQuant;
+use image::{
+    DynamicImage, ExtendedColorType,
+    codecs::gif::{GifDecoder, GifEncoder},
+};
+
+use super::FilterError;
+
+/// Reduces the image to a palette of `colors` colours and goes through a GIF encode/decode.
+/// GIF transparency is all or nothing, so alpha is thresholded when `transparency` is set
+/// and ignored otherwise.
+/// `speed` goes from 1 (best palette) to 30 (fastest).
+pub fn round_trip(
+    img: &DynamicImage,
+    colors: u16,
+    speed: u8,
+    transparency: bool,
+) -> Result<DynamicImage, FilterError> {
+    let (width, height) = (img.width(), img.height());
+    let mut rgba = img.to_rgba8();
+    for px in rgba.pixels_mut() {
+        px[3] = if !transparency || px[3] >= 128 {
+            255
+        } else {
+            0
+        };
+    }
+    let opaque: Vec<u8> = rgba
+        .pixels()
+        .filter(|px| px[3] == 255)
+        .flat_map(|px| px.0)
+        .collect();
+    if !opaque.is_empty() {
+        // Keep a palette entry for transparent pixels, GIF palettes being limited to 256 colours
+        let has_transparency = opaque.len() / 4 < rgba.pixels().len();
+        let colors = (colors as usize).clamp(2, 256 - has_transparency as usize);
+        let quantizer = NeuQuant::new(speed.clamp(1, 30) as i32, colors, &opaque);
+        for px in rgba.pixels_mut().filter(|px| px[3] == 255) {
+            quantizer.map_pixel(&mut px.0);
+            px[3] = 255;
+        }
+    }
+
+    let mut bytes = Vec::new();
+    {
+        // The file is only finished once the encoder is dropped
+        let mut encoder = GifEncoder::new(&mut bytes);
+        encoder.encode(rgba.as_raw(), width, height, ExtendedColorType::Rgba8)?;
+    }
+    let decoder = GifDecoder::new(Cursor::new(bytes))?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
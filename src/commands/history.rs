@@ -0,0 +1,220 @@
+use super::{CommandQueue, FilterCommand};
+
+/// Maximum number of undo steps kept in memory.
+const MAX_HISTORY: usize = 100;
+
+struct Entry {
+    label: String,
+    queue: Vec<FilterCommand>,
+}
+
+/// Undo/redo stacks of command queue snapshots.
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Entry>,
+    redo: Vec<Entry>,
+    /// Queue as of the last recorded change
+    current: Vec<FilterCommand>,
+    coalescing: bool,
+}
+
+fn describe(before: &[FilterCommand], after: &[FilterCommand]) -> String {
+    let changed = before.iter().zip(after).position(|(b, a)| b != a);
+    if after.len() == before.len() + 1 {
+        let i = changed.unwrap_or(before.len());
+        return format!("Add {}", after[i].filter.name());
+    }
+    if before.len() == after.len() + 1 {
+        let i = changed.unwrap_or(after.len());
+        return format!("Delete {}", before[i].filter.name());
+    }
+    if before.len() != after.len() {
+        return "Replace queue".into();
+    }
+    let Some(i) = changed else {
+        return "Edit queue".into();
+    };
+    let (b, a) = (&before[i], &after[i]);
+    if after.get(i + 1) == Some(b) && before.get(i + 1) == Some(a) {
+        format!("Move {}", b.filter.name())
+    } else if b.enabled != a.enabled {
+        let action = if a.enabled { "Enable" } else { "Disable" };
+        format!("{action} {}", a.filter.name())
+    } else if b.filter.name() != a.filter.name() {
+        "Replace queue".into()
+    } else {
+        format!("Edit {}", a.filter.name())
+    }
+}
+
+impl History {
+    /// Records the change made to the queue since the last call, if any. The queue is only
+    /// copied when it changed.
+    /// Changes made while `continuous` is set (e.g. dragging a slider) are merged into one entry.
+    pub fn record(&mut self, queue: &CommandQueue, continuous: bool) {
+        if self.current == queue.queue {
+            self.coalescing &= continuous;
+            return;
+        }
+        let label = describe(&self.current, &queue.queue);
+        let before = std::mem::replace(&mut self.current, queue.queue.clone());
+        let merge = self.coalescing && self.undo.last().is_some_and(|e| e.label == label);
+        if !merge {
+            self.undo.push(Entry {
+                label,
+                queue: before,
+            });
+            if self.undo.len() > MAX_HISTORY {
+                self.undo.remove(0);
+            }
+        }
+        self.redo.clear();
+        self.coalescing = continuous;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn undo(&mut self, queue: &mut CommandQueue) {
+        if let Some(entry) = self.undo.pop() {
+            let current = std::mem::replace(&mut queue.queue, entry.queue);
+            self.current = queue.queue.clone();
+            self.redo.push(Entry {
+                label: entry.label,
+                queue: current,
+            });
+            self.coalescing = false;
+        }
+    }
+
+    pub fn redo(&mut self, queue: &mut CommandQueue) {
+        if let Some(entry) = self.redo.pop() {
+            let current = std::mem::replace(&mut queue.queue, entry.queue);
+            self.current = queue.queue.clone();
+            self.undo.push(Entry {
+                label: entry.label,
+                queue: current,
+            });
+            self.coalescing = false;
+        }
+    }
+
+    /// Labels of the undoable changes, oldest first.
+    pub fn undo_labels(&self) -> impl Iterator<Item = &str> {
+        self.undo.iter().map(|e| e.label.as_str())
+    }
+
+    /// Labels of the redoable changes, next redo first.
+    pub fn redo_labels(&self) -> impl Iterator<Item = &str> {
+        self.redo.iter().rev().map(|e| e.label.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::filter::{AlphaMode, ImageFilter};
+
+    fn command(filter: ImageFilter) -> FilterCommand {
+        FilterCommand {
+            enabled: true,
+            filter,
+            alpha: AlphaMode::default(),
+        }
+    }
+
+    fn edit(
+        history: &mut History,
+        queue: &mut CommandQueue,
+        change: impl FnOnce(&mut CommandQueue),
+    ) {
+        change(queue);
+        history.record(queue, false);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut history = History::default();
+        let mut queue = CommandQueue::default();
+        edit(&mut history, &mut queue, |q| {
+            q.queue.push(command(ImageFilter::Invert))
+        });
+        edit(&mut history, &mut queue, |q| q.queue[0].enabled = false);
+        assert_eq!(
+            history.undo_labels().collect::<Vec<_>>(),
+            ["Add Invert", "Disable Invert"]
+        );
+        assert!(!history.can_redo());
+
+        history.undo(&mut queue);
+        assert!(queue.queue[0].enabled);
+        history.undo(&mut queue);
+        assert!(queue.queue.is_empty());
+        assert!(!history.can_undo());
+        assert_eq!(
+            history.redo_labels().collect::<Vec<_>>(),
+            ["Add Invert", "Disable Invert"]
+        );
+
+        history.redo(&mut queue);
+        assert_eq!(queue.queue.len(), 1);
+        // A new change drops the changes that were undone
+        edit(&mut history, &mut queue, |q| q.queue.clear());
+        assert!(!history.can_redo());
+        assert_eq!(
+            history.undo_labels().collect::<Vec<_>>(),
+            ["Add Invert", "Delete Invert"]
+        );
+    }
+
+    #[test]
+    fn continuous_changes_are_merged() {
+        let mut history = History::default();
+        let mut queue = CommandQueue::default();
+        queue
+            .queue
+            .push(command(ImageFilter::Brightness { percentage: 100 }));
+        history.record(&queue, false);
+        for percentage in [110, 120, 130] {
+            queue.queue[0].filter = ImageFilter::Brightness { percentage };
+            history.record(&queue, true);
+        }
+        // Releasing the slider ends the change
+        history.record(&queue, false);
+        queue.queue[0].filter = ImageFilter::Brightness { percentage: 140 };
+        history.record(&queue, true);
+
+        assert_eq!(history.undo_labels().count(), 3);
+        history.undo(&mut queue);
+        assert_eq!(
+            queue.queue[0].filter,
+            ImageFilter::Brightness { percentage: 130 }
+        );
+        history.undo(&mut queue);
+        assert_eq!(
+            queue.queue[0].filter,
+            ImageFilter::Brightness { percentage: 100 }
+        );
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut history = History::default();
+        let mut queue = CommandQueue::default();
+        for _ in 0..MAX_HISTORY + 10 {
+            edit(&mut history, &mut queue, |q| {
+                q.queue.push(command(ImageFilter::Invert))
+            });
+        }
+        assert_eq!(history.undo_labels().count(), MAX_HISTORY);
+        while history.can_undo() {
+            history.undo(&mut queue);
+        }
+        assert_eq!(queue.queue.len(), 10);
+    }
+}
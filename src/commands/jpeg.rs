@@ -0,0 +1,532 @@
+use std::{
+    array,
+    f32::consts::{FRAC_1_SQRT_2, PI},
+    io::Cursor,
+};
+
+use eframe::egui::{CollapsingHeader, DragValue, Grid, RadioButton, Slider, Ui, Widget};
+use image::{
+    ColorType, DynamicImage, GrayImage, ImageBuffer, Luma, RgbImage,
+    codecs::jpeg::{JpegDecoder, JpegEncoder},
+    imageops::{self, FilterType},
+};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::FilterError;
+
+// Tables K.1 and K.2 of the JPEG specification, in row-major order
+#[rustfmt::skip]
+const STD_LUMA_TABLE: [u8; 64] = [
+    16, 11, 10, 16,  24,  40,  51,  61,
+    12, 12, 14, 19,  26,  58,  60,  55,
+    14, 13, 16, 24,  40,  57,  69,  56,
+    14, 17, 22, 29,  51,  87,  80,  62,
+    18, 22, 37, 56,  68, 109, 103,  77,
+    24, 35, 55, 64,  81, 104, 113,  92,
+    49, 64, 78, 87, 103, 121, 120, 101,
+    72, 92, 95, 98, 112, 100, 103,  99,
+];
+
+#[rustfmt::skip]
+const STD_CHROMA_TABLE: [u8; 64] = [
+    17, 18, 24, 47, 99, 99, 99, 99,
+    18, 21, 26, 66, 99, 99, 99, 99,
+    24, 26, 56, 99, 99, 99, 99, 99,
+    47, 66, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+    99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// Change made to the image between JPEG generations, which is what makes the loss compound.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum JpegDrift {
+    #[default]
+    None,
+    /// Shift the image by a few pixels, wrapping around
+    Offset,
+    /// Shrink the image by about 2%
+    Resize,
+}
+
+/// Resolution of the colour channels relative to the brightness channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, full colour resolution
+    #[default]
+    Full,
+    /// 4:2:2, half horizontal colour resolution
+    Half,
+    /// 4:2:0, half horizontal and vertical colour resolution
+    Quarter,
+    /// Colour resolution divided by the given factors
+    Custom { horizontal: u8, vertical: u8 },
+}
+
+impl ChromaSubsampling {
+    fn factors(self) -> (u32, u32) {
+        match self {
+            Self::Full => (1, 1),
+            Self::Half => (2, 1),
+            Self::Quarter => (2, 2),
+            Self::Custom {
+                horizontal,
+                vertical,
+            } => (horizontal.max(1) as u32, vertical.max(1) as u32),
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Chroma");
+            ui.radio_value(self, Self::Full, "4:4:4");
+            ui.radio_value(self, Self::Half, "4:2:2");
+            ui.radio_value(self, Self::Quarter, "4:2:0");
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Custom { .. }),
+                    "Custom",
+                ))
+                .clicked()
+            {
+                *self = Self::Custom {
+                    horizontal: 8,
+                    vertical: 8,
+                };
+            }
+        });
+        if let Self::Custom {
+            horizontal,
+            vertical,
+        } = self
+        {
+            ui.horizontal(|ui| {
+                ui.label("Colour resolution divided by");
+                DragValue::new(horizontal).range(1..=32).ui(ui);
+                ui.label("x");
+                DragValue::new(vertical).range(1..=32).ui(ui);
+            });
+        }
+    }
+}
+
+/// Quantization tables used to throw away the details of every 8x8 block.
+/// The encoder only takes a quality, so any other tables are simulated by quantizing the
+/// DCT of the image directly, without an actual encode.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum QuantizationTables {
+    /// Standard tables scaled by the quality setting, as done by the encoder
+    #[default]
+    Standard,
+    /// Standard tables for the quality, with luma and chroma tables multiplied separately
+    Scaled { luma: f32, chroma: f32 },
+    /// Tables of 64 values in row-major order, the quality setting being ignored
+    Custom { luma: Vec<u8>, chroma: Vec<u8> },
+}
+
+/// Standard luma and chroma tables scaled for a quality, with the formula of libjpeg.
+fn standard_tables(quality: u8) -> [[u8; 64]; 2] {
+    let quality = quality.clamp(1, 100) as u32;
+    let scale = if quality < 50 {
+        5000 / quality
+    } else {
+        200 - quality * 2
+    };
+    [STD_LUMA_TABLE, STD_CHROMA_TABLE]
+        .map(|table| table.map(|v| ((v as u32 * scale + 50) / 100).clamp(1, 255) as u8))
+}
+
+impl QuantizationTables {
+    /// Whether the tables depend on the quality setting.
+    pub fn uses_quality(&self) -> bool {
+        !matches!(self, Self::Custom { .. })
+    }
+
+    /// Tables to quantize with, `None` when the encoder takes care of it.
+    fn values(&self, quality: u8) -> Result<Option<[[f32; 64]; 2]>, FilterError> {
+        match self {
+            Self::Standard => Ok(None),
+            Self::Scaled { luma, chroma } => {
+                let [l, c] = standard_tables(quality);
+                Ok(Some([
+                    l.map(|v| (v as f32 * luma).max(1.)),
+                    c.map(|v| (v as f32 * chroma).max(1.)),
+                ]))
+            }
+            Self::Custom { luma, chroma } => {
+                let table = |values: &[u8]| {
+                    let table: [u8; 64] = values
+                        .try_into()
+                        .map_err(|_| FilterError::InvalidQuantizationTable { len: values.len() })?;
+                    Ok::<_, FilterError>(table.map(|v| v.max(1) as f32))
+                };
+                Ok(Some([table(luma)?, table(chroma)?]))
+            }
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, quality: u8) {
+        ui.horizontal(|ui| {
+            ui.label("Quantization");
+            ui.radio_value(self, Self::Standard, "Standard");
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Scaled { .. }),
+                    "Scaled",
+                ))
+                .clicked()
+            {
+                *self = Self::Scaled {
+                    luma: 1.,
+                    chroma: 1.,
+                };
+            }
+            if ui
+                .add(RadioButton::new(
+                    matches!(self, Self::Custom { .. }),
+                    "Custom",
+                ))
+                .on_hover_text("Start from the standard tables for the current quality")
+                .clicked()
+            {
+                let [luma, chroma] = standard_tables(quality);
+                *self = Self::Custom {
+                    luma: luma.to_vec(),
+                    chroma: chroma.to_vec(),
+                };
+            }
+        });
+        if *self != Self::Standard {
+            ui.small(
+                "Simulated: blocks are quantized directly instead of going through the encoder",
+            );
+        }
+        match self {
+            Self::Standard => {}
+            Self::Scaled { luma, chroma } => {
+                Slider::new(luma, 0.1..=20.)
+                    .logarithmic(true)
+                    .text("Luma table scale")
+                    .ui(ui);
+                Slider::new(chroma, 0.1..=20.)
+                    .logarithmic(true)
+                    .text("Chroma table scale")
+                    .ui(ui);
+            }
+            Self::Custom { luma, chroma } => {
+                for (name, table) in [("Luma table", luma), ("Chroma table", chroma)] {
+                    CollapsingHeader::new(name).show(ui, |ui| {
+                        Grid::new(name).show(ui, |ui| {
+                            for (i, v) in table.iter_mut().enumerate() {
+                                DragValue::new(v).range(1..=255).ui(ui);
+                                if i % 8 == 7 {
+                                    ui.end_row();
+                                }
+                            }
+                        });
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Lossy parameters of one JPEG generation.
+#[derive(Clone, Copy)]
+pub struct JpegSettings<'a> {
+    pub quality: u8,
+    pub subsampling: ChromaSubsampling,
+    pub tables: &'a QuantizationTables,
+}
+
+/// A single channel of an image, with values between 0 and 255.
+struct Plane {
+    width: u32,
+    height: u32,
+    data: Vec<f32>,
+}
+
+/// DCT basis functions, `DCT[u][x]` being the weight of sample `x` in frequency `u`.
+fn dct_basis() -> [[f32; 8]; 8] {
+    array::from_fn(|u| {
+        let scale = if u == 0 { FRAC_1_SQRT_2 } else { 1. } / 2.;
+        array::from_fn(|x| scale * ((2 * x + 1) as f32 * u as f32 * PI / 16.).cos())
+    })
+}
+
+/// Computes `M * block * transpose(M)`, `M` being the basis or its transpose for the inverse.
+fn transform(block: &[[f32; 8]; 8], basis: &[[f32; 8]; 8], inverse: bool) -> [[f32; 8]; 8] {
+    let m = |i: usize, j: usize| if inverse { basis[j][i] } else { basis[i][j] };
+    let tmp: [[f32; 8]; 8] =
+        array::from_fn(|i| array::from_fn(|j| (0..8).map(|k| m(i, k) * block[k][j]).sum()));
+    array::from_fn(|i| array::from_fn(|j| (0..8).map(|k| tmp[i][k] * m(j, k)).sum()))
+}
+
+impl Plane {
+    fn resize(self, width: u32, height: u32) -> Self {
+        if self.width == width && self.height == height {
+            return self;
+        }
+        // Resizing clamps float samples between 0 and 1
+        let data: Vec<f32> = self.data.into_iter().map(|v| v / 255.).collect();
+        let buf: ImageBuffer<Luma<f32>, _> = ImageBuffer::from_raw(self.width, self.height, data)
+            .expect("plane buffer should match its size");
+        let resized = imageops::resize(&buf, width, height, FilterType::Triangle);
+        let data = resized.into_raw().into_iter().map(|v| v * 255.).collect();
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    /// Goes through the DCT, quantization and inverse DCT steps of JPEG.
+    fn quantize(&mut self, table: &[f32; 64]) {
+        let width = self.width as usize;
+        if width == 0 {
+            return;
+        }
+        let basis = dct_basis();
+        self.data.par_chunks_mut(width * 8).for_each(|rows| {
+            let height = rows.len() / width;
+            for bx in (0..width).step_by(8) {
+                // Blocks going over the edge repeat the last row and column
+                let block = array::from_fn(|y| {
+                    array::from_fn(|x| rows[y.min(height - 1) * width + (bx + x).min(width - 1)])
+                        .map(|v: f32| v - 128.)
+                });
+                let mut coefs = transform(&block, &basis, false);
+                for (i, c) in coefs.iter_mut().flatten().enumerate() {
+                    *c = (*c / table[i]).round() * table[i];
+                }
+                let block = transform(&coefs, &basis, true);
+                for (y, row) in block.iter().enumerate().take(height) {
+                    for (x, v) in row.iter().enumerate().take(width - bx) {
+                        rows[y * width + bx + x] = v + 128.;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Splits an 8-bit grayscale or RGB image into its Y, or Y, Cb and Cr planes.
+fn to_planes(img: &DynamicImage) -> Vec<Plane> {
+    let (width, height) = (img.width(), img.height());
+    let plane = |data| Plane {
+        width,
+        height,
+        data,
+    };
+    if img.color() == ColorType::L8 {
+        return vec![plane(img.as_bytes().iter().map(|v| *v as f32).collect())];
+    }
+    let (mut y, mut cb, mut cr) = (vec![], vec![], vec![]);
+    for px in img.as_bytes().chunks_exact(3) {
+        let [r, g, b] = [px[0], px[1], px[2]].map(|v| v as f32);
+        y.push(0.299 * r + 0.587 * g + 0.114 * b);
+        cb.push(-0.168736 * r - 0.331264 * g + 0.5 * b + 128.);
+        cr.push(0.5 * r - 0.418688 * g - 0.081312 * b + 128.);
+    }
+    vec![plane(y), plane(cb), plane(cr)]
+}
+
+fn from_planes(planes: Vec<Plane>) -> DynamicImage {
+    let to_u8 = |v: f32| v.round().clamp(0., 255.) as u8;
+    let (width, height) = (planes[0].width, planes[0].height);
+    let bytes: Vec<u8> = match &planes[..] {
+        [y] => y.data.iter().map(|v| to_u8(*v)).collect(),
+        [y, cb, cr] => (y.data.iter().zip(&cb.data).zip(&cr.data))
+            .flat_map(|((y, cb), cr)| {
+                let (cb, cr) = (cb - 128., cr - 128.);
+                [
+                    y + 1.402 * cr,
+                    y - 0.344136 * cb - 0.714136 * cr,
+                    y + 1.772 * cb,
+                ]
+                .map(to_u8)
+            })
+            .collect(),
+        _ => unreachable!("images have either one or three planes"),
+    };
+    match planes.len() {
+        1 => GrayImage::from_raw(width, height, bytes).map(Into::into),
+        _ => RgbImage::from_raw(width, height, bytes).map(Into::into),
+    }
+    .expect("buffer should match the plane size")
+}
+
+/// Encodes and decodes the image with the JPEG encoder.
+fn encode(img: &DynamicImage, quality: u8) -> Result<DynamicImage, FilterError> {
+    let mut bytes = Vec::new();
+    let encoder = JpegEncoder::new_with_quality(&mut bytes, quality);
+    img.write_with_encoder(encoder)?;
+    let decoder = JpegDecoder::new(Cursor::new(bytes))?;
+    Ok(DynamicImage::from_decoder(decoder)?)
+}
+
+/// Compresses an 8-bit grayscale or RGB image as JPEG and decodes it back.
+/// The encoder always keeps colour at full resolution with standard tables, so subsampling
+/// is done beforehand, and custom tables are applied by quantizing the image directly.
+fn round_trip(img: &DynamicImage, settings: JpegSettings) -> Result<DynamicImage, FilterError> {
+    let tables = settings.tables.values(settings.quality)?;
+    let (sx, sy) = settings.subsampling.factors();
+    if tables.is_none() && (sx, sy) == (1, 1) {
+        return encode(img, settings.quality);
+    }
+    let (width, height) = (img.width(), img.height());
+    let (chroma_width, chroma_height) = (width.div_ceil(sx), height.div_ceil(sy));
+    let mut planes: Vec<Plane> = to_planes(img)
+        .into_iter()
+        .enumerate()
+        .map(|(i, plane)| match i {
+            0 => plane,
+            _ => plane.resize(chroma_width, chroma_height),
+        })
+        .collect();
+    if let Some([luma, chroma]) = &tables {
+        for (i, plane) in planes.iter_mut().enumerate() {
+            plane.quantize(if i == 0 { luma } else { chroma });
+        }
+    }
+    let planes = planes
+        .into_iter()
+        .map(|plane| plane.resize(width, height))
+        .collect();
+    let img = from_planes(planes);
+    match tables {
+        Some(_) => Ok(img),
+        None => encode(&img, settings.quality),
+    }
+}
+
+/// Moves the pixels of an 8-bit image by the given offset, wrapping around the edges.
+fn roll(img: DynamicImage, dx: u32, dy: u32) -> DynamicImage {
+    let (width, height, color) = (img.width(), img.height(), img.color());
+    let channels = color.bytes_per_pixel() as usize;
+    let stride = width as usize * channels;
+    let mut bytes = img.into_bytes();
+    if stride > 0 {
+        bytes.rotate_right((dy % height) as usize * stride);
+        for row in bytes.chunks_exact_mut(stride) {
+            row.rotate_right((dx % width) as usize * channels);
+        }
+    }
+    match color {
+        ColorType::L8 => GrayImage::from_raw(width, height, bytes).map(Into::into),
+        _ => RgbImage::from_raw(width, height, bytes).map(Into::into),
+    }
+    .expect("buffer size should not change")
+}
+
+/// Runs one JPEG generation on an 8-bit grayscale or RGB image, moving the image slightly
+/// beforehand as requested by `drift` and moving it back afterwards, so that the block grid
+/// lands differently on every pass.
+pub fn generation(
+    img: DynamicImage,
+    settings: JpegSettings,
+    drift: JpegDrift,
+    (dx, dy): (u32, u32),
+) -> Result<DynamicImage, FilterError> {
+    let (width, height) = (img.width(), img.height());
+    match drift {
+        JpegDrift::None => round_trip(&img, settings),
+        JpegDrift::Offset => {
+            let img = round_trip(&roll(img, dx, dy), settings)?;
+            Ok(roll(img, width - dx % width, height - dy % height))
+        }
+        JpegDrift::Resize => {
+            let shrink = |n: u32| (n - n / 50).saturating_sub(1).max(1);
+            let img = img.resize_exact(shrink(width), shrink(height), FilterType::Triangle);
+            let img = round_trip(&img, settings)?;
+            Ok(img.resize_exact(width, height, FilterType::Triangle))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_tables_follow_libjpeg_scaling() {
+        assert_eq!(standard_tables(50), [STD_LUMA_TABLE, STD_CHROMA_TABLE]);
+        assert_eq!(standard_tables(100), [[1; 64]; 2]);
+        // Scaled up, then saturated to 8 bits
+        let [luma, chroma] = standard_tables(10);
+        assert_eq!(luma[0], 80);
+        assert_eq!(chroma[63], 255);
+        assert_eq!(standard_tables(0), standard_tables(1));
+    }
+
+    #[test]
+    fn table_values() {
+        assert!(QuantizationTables::Standard.values(80).unwrap().is_none());
+
+        let scaled = QuantizationTables::Scaled {
+            luma: 2.,
+            chroma: 0.01,
+        };
+        let [luma, chroma] = scaled.values(50).unwrap().unwrap();
+        assert_eq!(luma[0], 32.);
+        // Quantization steps never go below 1
+        assert_eq!(chroma, [1.; 64]);
+
+        let mut values = vec![0; 64];
+        values[1] = 40;
+        let custom = QuantizationTables::Custom {
+            luma: values.clone(),
+            chroma: values,
+        };
+        let [luma, _] = custom.values(10).unwrap().unwrap();
+        assert_eq!((luma[0], luma[1]), (1., 40.));
+
+        let short = QuantizationTables::Custom {
+            luma: vec![1; 63],
+            chroma: vec![1; 64],
+        };
+        assert!(matches!(
+            short.values(50),
+            Err(FilterError::InvalidQuantizationTable { len: 63 })
+        ));
+    }
+
+    #[test]
+    fn quantization_keeps_flat_blocks_and_drops_details() {
+        // 10 x 5 has partial blocks on both edges
+        let mut flat = Plane {
+            width: 10,
+            height: 5,
+            data: vec![200.; 50],
+        };
+        flat.quantize(&STD_LUMA_TABLE.map(|v| v as f32));
+        assert!(flat.data.iter().all(|v| (v - 200.).abs() < 1e-3));
+
+        let mut checkerboard = Plane {
+            width: 8,
+            height: 8,
+            data: (0..64)
+                .map(|i| if (i % 8 + i / 8) % 2 == 0 { 0. } else { 255. })
+                .collect(),
+        };
+        // Only the average of the block survives
+        let mut table = [1e6; 64];
+        table[0] = 1.;
+        checkerboard.quantize(&table);
+        assert!(checkerboard.data.iter().all(|v| (v - 127.5).abs() < 1e-3));
+    }
+
+    #[test]
+    fn subsampling_factors() {
+        assert_eq!(ChromaSubsampling::Full.factors(), (1, 1));
+        assert_eq!(ChromaSubsampling::Half.factors(), (2, 1));
+        assert_eq!(ChromaSubsampling::Quarter.factors(), (2, 2));
+        let custom = ChromaSubsampling::Custom {
+            horizontal: 0,
+            vertical: 3,
+        };
+        assert_eq!(custom.factors(), (1, 3));
+    }
+}
@@ -1,26 +1,127 @@
-use eframe::egui::{Align, Button, ComboBox, Layout, ScrollArea, Ui, style::ScrollStyle};
-use filter::ImageFilter;
+use std::{
+    fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU32, Ordering},
+    },
+};
+
+use eframe::egui::{
+    Align, Button, ComboBox, ImageButton, Layout, ScrollArea, Ui, load::SizedTexture,
+    style::ScrollStyle,
+};
+use filter::{AlphaMode, ImageFilter};
 use image::DynamicImage;
 use serde::{Deserialize, Serialize};
 
+mod blur;
+mod channel_shift;
+mod databend;
 mod filter;
+mod geometry;
+mod gif;
+mod history;
+mod jpeg;
+mod noise;
+mod pixel_sort;
+mod pixelate;
+mod quantize;
+mod scanline;
+
+pub use filter::FilterError;
+pub use history::History;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct FilterCommand {
     enabled: bool,
     filter: ImageFilter,
+    #[serde(default)]
+    alpha: AlphaMode,
+}
+
+#[derive(Debug, Default)]
+struct RenderState {
+    cancelled: AtomicBool,
+    /// Bits of the `f32` fraction of the current step done
+    step_progress: AtomicU32,
+}
+
+/// Shared flag used to stop a running queue early.
+/// Long filters also use it to report their progress through the current step.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<RenderState>);
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the current step done, between 0 and 1.
+    pub fn step_progress(&self) -> f32 {
+        f32::from_bits(self.0.step_progress.load(Ordering::Relaxed))
+    }
+
+    pub fn set_step_progress(&self, progress: f32) {
+        let progress = progress.clamp(0., 1.);
+        self.0
+            .step_progress
+            .store(progress.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn check(&self) -> Result<(), FilterError> {
+        if self.is_cancelled() {
+            Err(FilterError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
 }
 
 impl FilterCommand {
-    pub fn execute(self, img: DynamicImage) -> DynamicImage {
+    pub fn execute(
+        self,
+        img: DynamicImage,
+        cancel: &CancelToken,
+    ) -> Result<DynamicImage, FilterError> {
         if self.enabled {
-            self.filter.apply(img)
+            self.filter.apply(img, self.alpha, cancel)
         } else {
-            img
+            Ok(img)
         }
     }
 }
 
+/// A filter failure along with the queue step it happened at.
+#[derive(Debug)]
+pub struct QueueError {
+    pub step: usize,
+    pub name: &'static str,
+    pub error: FilterError,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "step {} ({}) failed: {}",
+            self.step + 1,
+            self.name,
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for QueueError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct CommandQueue {
     selected_filter: usize,
@@ -28,7 +129,10 @@ pub struct CommandQueue {
 }
 
 impl CommandQueue {
-    pub fn ui(&mut self, ui: &mut Ui) {
+    /// Shows the queue editor, with an optional thumbnail of the result of each step.
+    /// Returns the index of the step whose thumbnail was clicked, if any.
+    pub fn ui(&mut self, ui: &mut Ui, thumbnails: &[Option<SizedTexture>]) -> Option<usize> {
+        let mut clicked_step = None;
         ui.horizontal(|ui| {
             ComboBox::from_label("Add Filter")
                 .selected_text(ImageFilter::NAMES[self.selected_filter])
@@ -42,6 +146,7 @@ impl CommandQueue {
                 self.queue.push(FilterCommand {
                     enabled: true,
                     filter: ImageFilter::DEFAULTS[self.selected_filter].clone(),
+                    alpha: AlphaMode::default(),
                 });
             }
         });
@@ -55,6 +160,14 @@ impl CommandQueue {
                 for (i, filter) in self.queue.iter_mut().enumerate() {
                     ui.horizontal(|ui| {
                         ui.checkbox(&mut filter.enabled, "");
+                        if let Some(Some(thumbnail)) = thumbnails.get(i)
+                            && ui
+                                .add(ImageButton::new(*thumbnail))
+                                .on_hover_text("Show the image as of this step")
+                                .clicked()
+                        {
+                            clicked_step = Some(i);
+                        }
                         ui.label(format!("{}. {}", i + 1, filter.filter.name()));
                         ui.with_layout(Layout::right_to_left(Align::TOP), |ui| {
                             let bottom = i >= len - 1;
@@ -70,8 +183,13 @@ impl CommandQueue {
                             }
                         });
                     });
-                    ui.indent("wawa", |ui| {
-                        ui.add_enabled_ui(filter.enabled, |ui| filter.filter.ui(ui));
+                    ui.push_id(i, |ui| {
+                        ui.indent("wawa", |ui| {
+                            ui.add_enabled_ui(filter.enabled, |ui| {
+                                filter.filter.ui(ui);
+                                filter.alpha.ui(ui);
+                            });
+                        });
                     });
                 }
             });
@@ -88,10 +206,93 @@ impl CommandQueue {
         if self.queue.is_empty() {
             ui.small("There is nothing here.");
         }
+        clicked_step
+    }
+
+    /// Runs every command in order, calling `progress` with the number of steps done after each one.
+    /// Stops with [`FilterError::Cancelled`] as soon as `cancel` is triggered.
+    pub fn execute(
+        self,
+        img: DynamicImage,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(usize),
+    ) -> Result<DynamicImage, QueueError> {
+        self.execute_from(0, img, cancel, |step, _| progress(step))
+    }
+
+    /// Same as [`Self::execute`], but skips the first `start` commands, `img` being their result.
+    /// `progress` also receives the image as of each step.
+    pub fn execute_from(
+        self,
+        start: usize,
+        img: DynamicImage,
+        cancel: &CancelToken,
+        mut progress: impl FnMut(usize, &DynamicImage),
+    ) -> Result<DynamicImage, QueueError> {
+        let mut img = img;
+        for (step, command) in self.queue.into_iter().enumerate().skip(start) {
+            let name = command.filter.name();
+            img = cancel
+                .check()
+                .and_then(|_| command.execute(img, cancel))
+                .map_err(|error| QueueError { step, name, error })?;
+            cancel.set_step_progress(0.);
+            progress(step + 1, &img);
+        }
+        Ok(img)
+    }
+
+    /// Hash of the whole queue, used to detect changes.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.serialize()
+            .expect("filter commands should always serialize")
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Hashes identifying every queue prefix.
+    pub fn prefix_hashes(&self) -> Vec<u64> {
+        let mut hasher = DefaultHasher::new();
+        self.queue
+            .iter()
+            .map(|command| {
+                ron::to_string(command)
+                    .expect("filter commands should always serialize")
+                    .hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect()
+    }
+
+    /// Hashes identifying the result of every queue prefix, for caching purposes.
+    /// Disabled steps and every step following a non-deterministic filter have no hash.
+    pub fn result_hashes(&self) -> Vec<Option<u64>> {
+        let mut deterministic = true;
+        self.queue
+            .iter()
+            .zip(self.prefix_hashes())
+            .map(|(command, hash)| {
+                if command.enabled {
+                    deterministic &= command.filter.is_deterministic();
+                }
+                (command.enabled && deterministic).then_some(hash)
+            })
+            .collect()
+    }
+
+    /// Scales the pixel parameters of every filter, for rendering on an image scaled by `factor`.
+    pub fn scale_pixels(&mut self, factor: f32) {
+        for command in &mut self.queue {
+            command.filter.scale_pixels(factor);
+        }
     }
 
-    pub fn into_iter(self) -> std::vec::IntoIter<FilterCommand> {
-        self.queue.into_iter()
+    /// Whether an enabled filter gives a different result on a downscaled image.
+    pub fn is_scale_dependent(&self) -> bool {
+        self.queue
+            .iter()
+            .any(|command| command.enabled && command.filter.is_scale_dependent())
     }
 
     pub fn len(&self) -> usize {
@@ -107,3 +308,72 @@ impl CommandQueue {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(commands: &[(ImageFilter, bool)]) -> CommandQueue {
+        CommandQueue {
+            selected_filter: 0,
+            queue: commands
+                .iter()
+                .map(|(filter, enabled)| FilterCommand {
+                    enabled: *enabled,
+                    filter: filter.clone(),
+                    alpha: AlphaMode::default(),
+                })
+                .collect(),
+        }
+    }
+
+    fn noise(seed: Option<u64>) -> ImageFilter {
+        ImageFilter::Noise {
+            strength: 10,
+            seed,
+            model: Default::default(),
+        }
+    }
+
+    #[test]
+    fn result_hashes_skip_disabled_steps() {
+        let hashes = queue(&[
+            (ImageFilter::Invert, true),
+            (ImageFilter::BoxBlur, false),
+            (ImageFilter::Invert, true),
+        ])
+        .result_hashes();
+        assert!(hashes[0].is_some());
+        assert!(hashes[1].is_none());
+        assert!(hashes[2].is_some());
+        assert_ne!(hashes[0], hashes[2]);
+    }
+
+    #[test]
+    fn result_hashes_stop_at_unseeded_filters() {
+        let hashes = queue(&[
+            (ImageFilter::Invert, true),
+            (noise(None), true),
+            (ImageFilter::Invert, true),
+        ])
+        .result_hashes();
+        assert_eq!(
+            hashes.iter().map(Option::is_some).collect::<Vec<_>>(),
+            [true, false, false]
+        );
+        // Disabled unseeded filters don't change the result
+        let hashes = queue(&[(noise(None), false), (ImageFilter::Invert, true)]).result_hashes();
+        assert!(hashes[1].is_some());
+        let hashes = queue(&[(noise(Some(1)), true), (ImageFilter::Invert, true)]).result_hashes();
+        assert!(hashes.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn result_hashes_depend_on_previous_steps() {
+        let a = queue(&[(ImageFilter::Invert, true), (ImageFilter::BoxBlur, true)]);
+        let b = queue(&[(noise(Some(1)), true), (ImageFilter::BoxBlur, true)]);
+        assert_ne!(a.result_hashes()[1], b.result_hashes()[1]);
+        let c = queue(&[(ImageFilter::Invert, true), (noise(Some(1)), true)]);
+        assert_eq!(a.result_hashes()[0], c.result_hashes()[0]);
+    }
+}
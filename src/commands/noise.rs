@@ -0,0 +1,212 @@
+use std::f32::consts::TAU;
+
+use eframe::egui::{ComboBox, Slider, Ui, Widget};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+
+/// Kind of noise added by the `Noise` filter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum NoiseModel {
+    /// Darkens every pixel by a random amount, the original noise of the filter
+    #[default]
+    Darken,
+    /// Adds the same normally distributed value to every channel
+    Gaussian,
+    /// Turns random pixels black or white
+    SaltAndPepper,
+    /// Shot noise, stronger in the highlights like on a camera sensor
+    Poisson,
+    /// Adds a different normally distributed value to every channel
+    Color,
+    /// Smooth clumps of `size` pixels, strongest in the midtones
+    FilmGrain { size: f32 },
+}
+
+impl NoiseModel {
+    const ALL: &[Self] = &[
+        Self::Darken,
+        Self::Gaussian,
+        Self::SaltAndPepper,
+        Self::Poisson,
+        Self::Color,
+        Self::FilmGrain { size: 2. },
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Darken => "Darken",
+            Self::Gaussian => "Gaussian",
+            Self::SaltAndPepper => "Salt and pepper",
+            Self::Poisson => "Poisson",
+            Self::Color => "Colour",
+            Self::FilmGrain { .. } => "Film grain",
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ComboBox::from_label("Noise type")
+            .selected_text(self.name())
+            .show_ui(ui, |ui| {
+                for model in Self::ALL {
+                    let selected = std::mem::discriminant(self) == std::mem::discriminant(model);
+                    if ui.selectable_label(selected, model.name()).clicked() && !selected {
+                        *self = *model;
+                    }
+                }
+            });
+        if let Self::FilmGrain { size } = self {
+            Slider::new(size, 1.0..=10.0)
+                .text("Grain size")
+                .suffix("px")
+                .ui(ui);
+        }
+    }
+}
+
+/// Standard normal value, with the Box-Muller transform.
+fn gaussian(random: &mut ChaCha20Rng) -> f32 {
+    let u: f32 = 1. - random.random::<f32>();
+    let v: f32 = random.random();
+    (-2. * u.ln()).sqrt() * (TAU * v).cos()
+}
+
+/// Number of events of a Poisson process with the mean `lambda`.
+fn poisson(random: &mut ChaCha20Rng, lambda: f32) -> f32 {
+    if lambda > 30. {
+        // Close enough to a normal distribution, and much faster
+        return (lambda + lambda.sqrt() * gaussian(random)).max(0.);
+    }
+    let limit = (-lambda).exp();
+    let mut k = 0.;
+    let mut p: f32 = random.random();
+    while p > limit {
+        k += 1.;
+        p *= random.random::<f32>();
+    }
+    k
+}
+
+/// Generates the noise of an image row by row. Every row has its own random stream,
+/// so rows can be processed in any order and still give the same image for a seed.
+pub struct NoiseGenerator {
+    model: NoiseModel,
+    strength: f32,
+    seed: u64,
+    width: u32,
+    /// Coarse random field of the film grain, `grain_width` values per row
+    grain: Vec<f32>,
+    grain_width: usize,
+}
+
+impl NoiseGenerator {
+    /// `strength` goes from 0 to 1.
+    pub fn new(model: NoiseModel, strength: f32, seed: u64, width: u32, height: u32) -> Self {
+        let (mut grain, mut grain_width) = (Vec::new(), 0);
+        if let NoiseModel::FilmGrain { size } = model {
+            let size = size.max(1.);
+            grain_width = (width as f32 / size).ceil() as usize + 2;
+            let grain_height = (height as f32 / size).ceil() as usize + 2;
+            let mut random = ChaCha20Rng::seed_from_u64(seed);
+            random.set_stream(u64::MAX);
+            grain = (0..grain_width * grain_height)
+                .map(|_| gaussian(&mut random))
+                .collect();
+        }
+        Self {
+            model,
+            strength,
+            seed,
+            width,
+            grain,
+            grain_width,
+        }
+    }
+
+    /// Film grain value at the pixel, interpolated between the points of the coarse field.
+    fn grain(&self, x: usize, y: u32, size: f32) -> f32 {
+        let (gx, gy) = (x as f32 / size.max(1.), y as f32 / size.max(1.));
+        let (x0, y0) = (gx.floor(), gy.floor());
+        let (fx, fy) = (gx - x0, gy - y0);
+        let at = |dx: usize, dy: usize| {
+            self.grain[(y0 as usize + dy) * self.grain_width + x0 as usize + dx]
+        };
+        let top = at(0, 0) * (1. - fx) + at(1, 0) * fx;
+        let bottom = at(0, 1) * (1. - fx) + at(1, 1) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+
+    /// Adds noise to the first `changed` channels of every pixel of row `y`.
+    pub fn row(&self, y: u32, row: &mut [f32], channels: usize, changed: usize, max: f32) {
+        let mut random = ChaCha20Rng::seed_from_u64(self.seed);
+        let strength = self.strength;
+        let sigma = strength * 0.25 * max;
+        let pixels = row.chunks_exact_mut(channels);
+        match self.model {
+            NoiseModel::Darken => {
+                // Each pixel draws one word from the stream, so seeking to the start of
+                // the row gives the same noise as generating the whole image in order
+                random.set_word_pos(y as u128 * self.width as u128);
+                for px in pixels {
+                    let rnoise = random.random_range(0.0..=1.0);
+                    let noise = 1.0 - (rnoise * strength);
+                    for c in &mut px[..changed] {
+                        *c = ((*c / max) * noise) * max;
+                    }
+                }
+                return;
+            }
+            NoiseModel::Gaussian => {
+                random.set_stream(y as u64);
+                for px in pixels {
+                    let noise = gaussian(&mut random) * sigma;
+                    for c in &mut px[..changed] {
+                        *c += noise;
+                    }
+                }
+            }
+            NoiseModel::SaltAndPepper => {
+                random.set_stream(y as u64);
+                for px in pixels {
+                    if random.random::<f32>() < strength * 0.25 {
+                        let value = if random.random() { max } else { 0. };
+                        px[..changed].fill(value);
+                    }
+                }
+            }
+            NoiseModel::Poisson => {
+                random.set_stream(y as u64);
+                // Photons caught by a fully lit pixel, fewer photons giving more noise
+                let photons = 10. / strength.max(0.01).powi(2);
+                for px in pixels {
+                    for c in &mut px[..changed] {
+                        *c = poisson(&mut random, *c / max * photons) / photons * max;
+                    }
+                }
+            }
+            NoiseModel::Color => {
+                random.set_stream(y as u64);
+                for px in pixels {
+                    for c in &mut px[..changed] {
+                        *c += gaussian(&mut random) * sigma;
+                    }
+                }
+            }
+            NoiseModel::FilmGrain { size } => {
+                for (x, px) in pixels.enumerate() {
+                    let luma = (0.299 * px[0] + 0.587 * px[1] + 0.114 * px[2]) / max;
+                    let midtones = 4. * luma.clamp(0., 1.) * (1. - luma.clamp(0., 1.));
+                    let noise = self.grain(x, y, size) * sigma * (0.25 + 0.75 * midtones);
+                    for c in &mut px[..changed] {
+                        *c += noise;
+                    }
+                }
+            }
+        }
+        for px in row.chunks_exact_mut(channels) {
+            for c in &mut px[..changed] {
+                *c = c.clamp(0., max);
+            }
+        }
+    }
+}
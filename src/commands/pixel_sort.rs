@@ -0,0 +1,259 @@
+use eframe::egui::{ComboBox, DragValue, RadioButton, Slider, Ui, Widget, ecolor::hsv_from_rgb};
+use image::Rgba32FImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+/// Lines along which pixels are sorted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SortDirection {
+    #[default]
+    Rows,
+    Columns,
+    /// Clockwise from the rows, in degrees
+    Angle(f32),
+}
+
+impl SortDirection {
+    fn degrees(self) -> f32 {
+        match self {
+            Self::Rows => 0.,
+            Self::Columns => 90.,
+            Self::Angle(degrees) => degrees,
+        }
+    }
+}
+
+/// Value of a pixel used to find the spans to sort and to sort them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PixelKey {
+    #[default]
+    Luminance,
+    Hue,
+    Saturation,
+    Red,
+    Green,
+    Blue,
+}
+
+impl PixelKey {
+    const ALL: &[Self] = &[
+        Self::Luminance,
+        Self::Hue,
+        Self::Saturation,
+        Self::Red,
+        Self::Green,
+        Self::Blue,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Luminance => "Luminance",
+            Self::Hue => "Hue",
+            Self::Saturation => "Saturation",
+            Self::Red => "Red",
+            Self::Green => "Green",
+            Self::Blue => "Blue",
+        }
+    }
+
+    /// Value of the key between 0 and 1.
+    fn value(self, px: &[f32]) -> f32 {
+        let [r, g, b] = [px[0], px[1], px[2]].map(|c| c.clamp(0., 1.));
+        match self {
+            Self::Luminance => 0.299 * r + 0.587 * g + 0.114 * b,
+            Self::Hue => hsv_from_rgb([r, g, b]).0,
+            Self::Saturation => hsv_from_rgb([r, g, b]).1,
+            Self::Red => r,
+            Self::Green => g,
+            Self::Blue => b,
+        }
+    }
+
+    fn combo(&mut self, ui: &mut Ui, label: &str) {
+        ComboBox::from_label(label)
+            .selected_text(self.name())
+            .show_ui(ui, |ui| {
+                for k in Self::ALL {
+                    ui.selectable_value(self, *k, k.name());
+                }
+            });
+    }
+}
+
+/// Settings UI of the `PixelSort` filter.
+pub fn ui(
+    ui: &mut Ui,
+    direction: &mut SortDirection,
+    span_key: &mut PixelKey,
+    lower: &mut f32,
+    upper: &mut f32,
+    sort_key: &mut PixelKey,
+    reverse: &mut bool,
+) {
+    ui.horizontal(|ui| {
+        ui.radio_value(direction, SortDirection::Rows, "Rows");
+        ui.radio_value(direction, SortDirection::Columns, "Columns");
+        if ui
+            .add(RadioButton::new(
+                matches!(direction, SortDirection::Angle(_)),
+                "Angle",
+            ))
+            .clicked()
+        {
+            *direction = SortDirection::Angle(45.);
+        }
+        if let SortDirection::Angle(degrees) = direction {
+            DragValue::new(degrees)
+                .range(-180.0..=180.0)
+                .suffix("°")
+                .ui(ui);
+        }
+    });
+    span_key.combo(ui, "Spans of");
+    Slider::new(lower, 0.0..=1.0).text("Lower threshold").ui(ui);
+    Slider::new(upper, 0.0..=1.0).text("Upper threshold").ui(ui);
+    sort_key.combo(ui, "Sort by");
+    ui.checkbox(reverse, "Reverse");
+}
+
+/// Splits `values` into the slices of every line, `starts` being the index where each line
+/// starts followed by the total length.
+fn split_lines<'a>(mut values: &'a mut [u32], starts: &[usize]) -> Vec<&'a mut [u32]> {
+    starts
+        .windows(2)
+        .map(|range| {
+            let (line, rest) = std::mem::take(&mut values).split_at_mut(range[1] - range[0]);
+            values = rest;
+            line
+        })
+        .collect()
+}
+
+/// Pixel indices of every line crossing the image in the given direction, in order along
+/// the line. Every pixel belongs to exactly one line. The lines are stored one after the
+/// other, along with the index where each of them starts followed by the pixel count.
+fn lines(
+    width: u32,
+    height: u32,
+    degrees: f32,
+    cancel: &CancelToken,
+) -> Result<(Vec<u32>, Vec<usize>), FilterError> {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let offset = |x: u32, y: u32| (-(x as f32) * sin + y as f32 * cos).round() as i64;
+    let corners = [
+        (0, 0),
+        (width - 1, 0),
+        (0, height - 1),
+        (width - 1, height - 1),
+    ];
+    let first = corners
+        .iter()
+        .map(|&(x, y)| offset(x, y))
+        .min()
+        .unwrap_or(0);
+    let last = corners
+        .iter()
+        .map(|&(x, y)| offset(x, y))
+        .max()
+        .unwrap_or(0);
+    let labels: Vec<u32> = (0..width * height)
+        .into_par_iter()
+        .map(|i| (offset(i % width, i / width) - first) as u32)
+        .collect();
+
+    // Counting sort of the pixels by line, keeping them in row-major order within a line
+    let mut starts = vec![0; (last - first + 2) as usize];
+    for &label in &labels {
+        starts[label as usize + 1] += 1;
+    }
+    for i in 1..starts.len() {
+        starts[i] += starts[i - 1];
+    }
+    let mut next = starts.clone();
+    let mut order = vec![0; labels.len()];
+    for (i, &label) in labels.iter().enumerate() {
+        order[next[label as usize]] = i as u32;
+        next[label as usize] += 1;
+    }
+
+    let along = |i: u32| (i % width) as f32 * cos + (i / width) as f32 * sin;
+    split_lines(&mut order, &starts)
+        .into_par_iter()
+        .try_for_each(|line| {
+            cancel.check()?;
+            line.sort_by(|a, b| along(*a).total_cmp(&along(*b)));
+            Ok::<_, FilterError>(())
+        })?;
+    Ok((order, starts))
+}
+
+/// Sorts the spans of pixels whose `span_key` is within `threshold`, along every line.
+/// The bounds of `threshold` can be given in any order.
+pub fn sort(
+    img: &mut Rgba32FImage,
+    direction: SortDirection,
+    span_key: PixelKey,
+    threshold: (f32, f32),
+    sort_key: PixelKey,
+    reverse: bool,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let (lower, upper) = if threshold.0 <= threshold.1 {
+        threshold
+    } else {
+        (threshold.1, threshold.0)
+    };
+    let pixels: &[f32] = img.as_raw();
+    let in_span: Vec<bool> = pixels
+        .par_chunks_exact(4)
+        .map(|px| (lower..=upper).contains(&span_key.value(px)))
+        .collect();
+    let keys: Vec<f32> = pixels
+        .par_chunks_exact(4)
+        .map(|px| sort_key.value(px))
+        .collect();
+    let (order, starts) = lines(width, height, direction.degrees(), cancel)?;
+    cancel.set_step_progress(0.4);
+
+    // Pixels along every line once their spans are sorted
+    let mut sorted = order.clone();
+    split_lines(&mut sorted, &starts)
+        .into_par_iter()
+        .try_for_each(|line| {
+            cancel.check()?;
+            for span in line.chunk_by_mut(|a, b| in_span[*a as usize] == in_span[*b as usize]) {
+                if in_span[span[0] as usize] {
+                    span.sort_by(|a, b| {
+                        let order = keys[*a as usize].total_cmp(&keys[*b as usize]);
+                        if reverse { order.reverse() } else { order }
+                    });
+                }
+            }
+            Ok::<_, FilterError>(())
+        })?;
+    cancel.set_step_progress(0.8);
+
+    // Where every pixel of the result comes from
+    let mut sources = vec![0; order.len()];
+    for (&to, &from) in order.iter().zip(&sorted) {
+        sources[to as usize] = from;
+    }
+    let original = img.as_raw().clone();
+    let width = width as usize;
+    img.par_chunks_mut(width * 4)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            cancel.check()?;
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let from = sources[y * width + x] as usize * 4;
+                px.copy_from_slice(&original[from..from + 4]);
+            }
+            Ok(())
+        })
+}
@@ -0,0 +1,283 @@
+use eframe::egui::{ComboBox, Slider, Ui, Widget};
+use image::Rgba32FImage;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+/// Shape of the cells the image is divided into.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum CellShape {
+    #[default]
+    Square,
+    Hexagon,
+    Triangle,
+    /// Irregular cells around points scattered over a grid
+    Voronoi,
+}
+
+impl CellShape {
+    const ALL: &[Self] = &[Self::Square, Self::Hexagon, Self::Triangle, Self::Voronoi];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Square => "Square",
+            Self::Hexagon => "Hexagon",
+            Self::Triangle => "Triangle",
+            Self::Voronoi => "Voronoi",
+        }
+    }
+
+    /// Coordinates of the cell containing the pixel `(x, y)`.
+    fn cell(self, x: u32, y: u32, size: f32) -> (i32, i32) {
+        // Sample at the centre of the pixel
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        match self {
+            Self::Square => ((x / size) as i32, (y / size) as i32),
+            Self::Hexagon => hexagon(x, y, size),
+            Self::Triangle => {
+                let row = (y / size).floor();
+                let fy = y / size - row;
+                let column = (x / size).floor();
+                let fx = x / size - column;
+                // Upward triangles are centred on the columns, downward ones sit between them
+                let shift = (fx - 0.5 > fy / 2.) as i32 - (0.5 - fx > fy / 2.) as i32;
+                (2 * column as i32 + shift, row as i32)
+            }
+            Self::Voronoi => {
+                let (gx, gy) = ((x / size).floor() as i32, (y / size).floor() as i32);
+                let mut nearest = (gx, gy);
+                let mut best = f32::INFINITY;
+                for cy in gy - 1..=gy + 1 {
+                    for cx in gx - 1..=gx + 1 {
+                        let (px, py) = voronoi_point(cx, cy);
+                        let dx = (cx as f32 + px) * size - x;
+                        let dy = (cy as f32 + py) * size - y;
+                        let distance = dx * dx + dy * dy;
+                        if distance < best {
+                            best = distance;
+                            nearest = (cx, cy);
+                        }
+                    }
+                }
+                nearest
+            }
+        }
+    }
+}
+
+/// Axial coordinates of the pointy-top hexagon containing `(x, y)`, `size` being the
+/// distance between the centres of two neighbouring hexagons.
+fn hexagon(x: f32, y: f32, size: f32) -> (i32, i32) {
+    let radius = size / 3f32.sqrt();
+    let q = (3f32.sqrt() / 3. * x - y / 3.) / radius;
+    let r = (2. / 3. * y) / radius;
+    // Round the cube coordinates, fixing the one with the largest error
+    let s = -q - r;
+    let (mut rq, mut rr, rs) = (q.round(), r.round(), s.round());
+    let (dq, dr, ds) = ((rq - q).abs(), (rr - r).abs(), (rs - s).abs());
+    if dq > dr && dq > ds {
+        rq = -rr - rs;
+    } else if dr > ds {
+        rr = -rq - rs;
+    }
+    (rq as i32, rr as i32)
+}
+
+/// Position of the point of a Voronoi grid cell, relative to the cell. The points are
+/// derived from the cell coordinates, so the same image is always split the same way.
+fn voronoi_point(x: i32, y: i32) -> (f32, f32) {
+    let mut h = (x as u32).wrapping_mul(0x9E37_79B1) ^ (y as u32).wrapping_mul(0x85EB_CA77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B_3C6D);
+    h ^= h >> 12;
+    let a = (h & 0xFFFF) as f32 / 65535.;
+    let b = (h >> 16) as f32 / 65535.;
+    (a, b)
+}
+
+/// How the colour of a cell is picked.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Averaging {
+    #[default]
+    Mean,
+    /// Median of every channel
+    Median,
+    /// First pixel of the cell, going left to right and top to bottom
+    TopLeft,
+}
+
+impl Averaging {
+    const ALL: &[Self] = &[Self::Mean, Self::Median, Self::TopLeft];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Mean => "Mean",
+            Self::Median => "Median",
+            Self::TopLeft => "Top-left sample",
+        }
+    }
+}
+
+/// Settings UI of the `Pixelate` filter.
+pub fn ui(ui: &mut Ui, size: &mut u32, shape: &mut CellShape, averaging: &mut Averaging) {
+    Slider::new(size, 2..=256)
+        .logarithmic(true)
+        .text("Block size")
+        .suffix("px")
+        .ui(ui);
+    ComboBox::from_label("Cell shape")
+        .selected_text(shape.name())
+        .show_ui(ui, |ui| {
+            for s in CellShape::ALL {
+                ui.selectable_value(shape, *s, s.name());
+            }
+        });
+    ComboBox::from_label("Colour")
+        .selected_text(averaging.name())
+        .show_ui(ui, |ui| {
+            for a in Averaging::ALL {
+                ui.selectable_value(averaging, *a, a.name());
+            }
+        });
+}
+
+/// Fills every cell of the image with a single colour.
+pub fn pixelate(
+    img: &mut Rgba32FImage,
+    size: u32,
+    shape: CellShape,
+    averaging: Averaging,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let size = size.max(1) as f32;
+
+    // Label every pixel with its cell, then number the cells densely
+    let mut cells = vec![(0, 0); width as usize * height as usize];
+    cells
+        .par_chunks_mut(width as usize)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            cancel.check()?;
+            for (x, cell) in row.iter_mut().enumerate() {
+                *cell = shape.cell(x as u32, y as u32, size);
+            }
+            Ok::<_, FilterError>(())
+        })?;
+    let (min_x, max_x, min_y) = cells.iter().fold(
+        (i32::MAX, i32::MIN, i32::MAX),
+        |(min_x, max_x, min_y), &(x, y)| (min_x.min(x), max_x.max(x), min_y.min(y)),
+    );
+    let columns = (max_x - min_x + 1) as usize;
+    let labels: Vec<usize> = cells
+        .par_iter()
+        .map(|&(x, y)| (y - min_y) as usize * columns + (x - min_x) as usize)
+        .collect();
+    drop(cells);
+    let count = labels.iter().max().map_or(0, |m| m + 1);
+    cancel.set_step_progress(0.3);
+
+    let pixels: &[f32] = img.as_raw();
+    let colors: Vec<[f32; 4]> = match averaging {
+        Averaging::Mean => {
+            let mut sums = vec![([0f64; 4], 0u32); count];
+            for (px, &label) in pixels.chunks_exact(4).zip(&labels) {
+                let (sum, n) = &mut sums[label];
+                for (s, c) in sum.iter_mut().zip(px) {
+                    *s += *c as f64;
+                }
+                *n += 1;
+            }
+            sums.into_iter()
+                .map(|(sum, n)| sum.map(|s| (s / n.max(1) as f64) as f32))
+                .collect()
+        }
+        Averaging::TopLeft => {
+            let mut colors = vec![None; count];
+            for (px, &label) in pixels.chunks_exact(4).zip(&labels) {
+                colors[label].get_or_insert([px[0], px[1], px[2], px[3]]);
+            }
+            colors.into_iter().map(Option::unwrap_or_default).collect()
+        }
+        Averaging::Median => {
+            // Group the pixel indices by cell with a counting sort
+            let mut starts = vec![0usize; count + 1];
+            for &label in &labels {
+                starts[label + 1] += 1;
+            }
+            for i in 1..starts.len() {
+                starts[i] += starts[i - 1];
+            }
+            let mut next = starts.clone();
+            let mut order = vec![0usize; labels.len()];
+            for (i, &label) in labels.iter().enumerate() {
+                order[next[label]] = i;
+                next[label] += 1;
+            }
+            starts
+                .par_windows(2)
+                .map_init(Vec::new, |values, range| {
+                    let members = &order[range[0]..range[1]];
+                    let mut color = [0.; 4];
+                    if members.is_empty() {
+                        return color;
+                    }
+                    for (c, out) in color.iter_mut().enumerate() {
+                        values.clear();
+                        values.extend(members.iter().map(|&i| pixels[i * 4 + c]));
+                        let middle = values.len() / 2;
+                        *out = *values.select_nth_unstable_by(middle, f32::total_cmp).1;
+                    }
+                    color
+                })
+                .collect()
+        }
+    };
+    cancel.check()?;
+    cancel.set_step_progress(0.8);
+
+    img.par_chunks_mut(4)
+        .zip(labels.par_iter())
+        .for_each(|(px, &label)| px.copy_from_slice(&colors[label]));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hexagon_centres_and_neighbourhoods() {
+        let size = 12.;
+        let height = size * 3f32.sqrt() / 2.;
+        for (q, r) in [(0, 0), (1, 0), (0, 1), (-2, 3), (4, -1)] {
+            let (cx, cy) = (size * (q as f32 + r as f32 / 2.), height * r as f32);
+            assert_eq!(hexagon(cx, cy, size), (q, r));
+            // Points well inside the hexagon belong to it too
+            for (dx, dy) in [(4., 0.), (-4., 0.), (0., 4.), (3., -3.)] {
+                assert_eq!(hexagon(cx + dx, cy + dy, size), (q, r));
+            }
+        }
+        // Halfway between two centres of a row, a little off the shared edge
+        assert_eq!(hexagon(size / 2. - 0.1, 0., size), (0, 0));
+        assert_eq!(hexagon(size / 2. + 0.1, 0., size), (1, 0));
+    }
+
+    #[test]
+    fn triangle_cells() {
+        let cell = |x, y| CellShape::Triangle.cell(x, y, 10.);
+        // Base of the upward triangle of the first column
+        assert_eq!(cell(5, 9), (0, 0));
+        assert_eq!(cell(0, 9), (0, 0));
+        // Top corners belong to the downward triangles on each side
+        assert_eq!(cell(0, 0), (-1, 0));
+        assert_eq!(cell(9, 0), (1, 0));
+        assert_eq!(cell(10, 0), (1, 0));
+        assert_eq!(cell(15, 9), (2, 0));
+        assert_eq!(cell(15, 19), (2, 1));
+    }
+}
@@ -0,0 +1,410 @@
+use eframe::egui::{ComboBox, Slider, Ui, Widget};
+use image::{Rgba32FImage, RgbaImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+const CGA: &[[u8; 3]] = &[[0, 0, 0], [85, 255, 255], [255, 85, 255], [255, 255, 255]];
+const GAME_BOY: &[[u8; 3]] = &[[15, 56, 15], [48, 98, 48], [139, 172, 15], [155, 188, 15]];
+const KMEANS_ITERATIONS: usize = 10;
+
+/// Where the colours of the palette come from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PaletteSource {
+    #[default]
+    MedianCut,
+    /// Median cut palette refined with k-means clustering
+    KMeans,
+    /// Black, cyan, magenta and white
+    Cga,
+    /// Four shades of green
+    GameBoy,
+    /// The 216 colours of the web-safe palette
+    WebSafe,
+}
+
+impl PaletteSource {
+    const ALL: &[Self] = &[
+        Self::MedianCut,
+        Self::KMeans,
+        Self::Cga,
+        Self::GameBoy,
+        Self::WebSafe,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::MedianCut => "Median cut",
+            Self::KMeans => "K-means",
+            Self::Cga => "CGA",
+            Self::GameBoy => "Game Boy",
+            Self::WebSafe => "Web-safe",
+        }
+    }
+
+    /// Whether the palette is computed from the image.
+    fn is_adaptive(self) -> bool {
+        matches!(self, Self::MedianCut | Self::KMeans)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Dithering {
+    #[default]
+    None,
+    FloydSteinberg,
+    Atkinson,
+    Bayer2,
+    Bayer4,
+    Bayer8,
+}
+
+impl Dithering {
+    const ALL: &[Self] = &[
+        Self::None,
+        Self::FloydSteinberg,
+        Self::Atkinson,
+        Self::Bayer2,
+        Self::Bayer4,
+        Self::Bayer8,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::None => "None",
+            Self::FloydSteinberg => "Floyd-Steinberg",
+            Self::Atkinson => "Atkinson",
+            Self::Bayer2 => "Bayer 2x2",
+            Self::Bayer4 => "Bayer 4x4",
+            Self::Bayer8 => "Bayer 8x8",
+        }
+    }
+
+    /// Neighbours receiving the quantization error, as (dx, dy, weight).
+    fn diffusion(self) -> Option<&'static [(i32, i32, f32)]> {
+        match self {
+            Self::FloydSteinberg => Some(&[
+                (1, 0, 7. / 16.),
+                (-1, 1, 3. / 16.),
+                (0, 1, 5. / 16.),
+                (1, 1, 1. / 16.),
+            ]),
+            // Only 6/8 of the error is spread, which keeps more contrast
+            Self::Atkinson => Some(&[
+                (1, 0, 1. / 8.),
+                (2, 0, 1. / 8.),
+                (-1, 1, 1. / 8.),
+                (0, 1, 1. / 8.),
+                (1, 1, 1. / 8.),
+                (0, 2, 1. / 8.),
+            ]),
+            _ => None,
+        }
+    }
+
+    fn bayer_size(self) -> Option<usize> {
+        match self {
+            Self::Bayer2 => Some(2),
+            Self::Bayer4 => Some(4),
+            Self::Bayer8 => Some(8),
+            _ => None,
+        }
+    }
+}
+
+/// Settings UI of the `Quantize` filter.
+pub fn ui(ui: &mut Ui, colors: &mut u16, palette: &mut PaletteSource, dithering: &mut Dithering) {
+    ComboBox::from_label("Palette")
+        .selected_text(palette.name())
+        .show_ui(ui, |ui| {
+            for p in PaletteSource::ALL {
+                ui.selectable_value(palette, *p, p.name());
+            }
+        });
+    if palette.is_adaptive() {
+        Slider::new(colors, 2..=256)
+            .logarithmic(true)
+            .text("Colours")
+            .ui(ui);
+    }
+    ComboBox::from_label("Dithering")
+        .selected_text(dithering.name())
+        .show_ui(ui, |ui| {
+            for d in Dithering::ALL {
+                ui.selectable_value(dithering, *d, d.name());
+            }
+        });
+}
+
+/// Colours of the image, grouped in bins of 5 bits per channel.
+#[derive(Clone)]
+struct Bin {
+    color: [f32; 3],
+    count: f32,
+}
+
+fn histogram(img: &RgbaImage) -> Vec<Bin> {
+    let mut bins = vec![([0u64; 3], 0u64); 1 << 15];
+    // Fully transparent pixels often have meaningless colours
+    for px in img.pixels().filter(|px| px[3] > 0) {
+        let [r, g, b, _] = px.0;
+        let key = (r as usize >> 3) << 10 | (g as usize >> 3) << 5 | b as usize >> 3;
+        let (sum, count) = &mut bins[key];
+        for (s, c) in sum.iter_mut().zip([r, g, b]) {
+            *s += c as u64;
+        }
+        *count += 1;
+    }
+    bins.into_iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(sum, count)| Bin {
+            color: sum.map(|s| s as f32 / count as f32),
+            count: count as f32,
+        })
+        .collect()
+}
+
+fn mean(bins: &[Bin]) -> [f32; 3] {
+    let total: f32 = bins.iter().map(|b| b.count).sum();
+    let mut color = [0.; 3];
+    for bin in bins {
+        for (c, v) in color.iter_mut().zip(bin.color) {
+            *c += v * bin.count / total;
+        }
+    }
+    color
+}
+
+/// Range of the bins along their widest channel, with that channel.
+fn widest_channel(bins: &[Bin]) -> (f32, usize) {
+    (0..3)
+        .map(|c| {
+            let values = bins.iter().map(|b| b.color[c]);
+            let min = values.clone().fold(f32::MAX, f32::min);
+            let max = values.fold(f32::MIN, f32::max);
+            (max - min, c)
+        })
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .unwrap_or((0., 0))
+}
+
+fn median_cut(bins: Vec<Bin>, colors: usize) -> Vec<[f32; 3]> {
+    if bins.is_empty() {
+        return vec![[0.; 3]];
+    }
+    let mut boxes = vec![bins];
+    while boxes.len() < colors {
+        let Some((i, channel)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .map(|(i, b)| (i, widest_channel(b)))
+            .max_by(|a, b| a.1.0.total_cmp(&b.1.0))
+            .map(|(i, (_, channel))| (i, channel))
+        else {
+            break;
+        };
+        let mut bx = boxes.swap_remove(i);
+        bx.sort_by(|a, b| a.color[channel].total_cmp(&b.color[channel]));
+        let half = bx.iter().map(|b| b.count).sum::<f32>() / 2.;
+        let mut acc = 0.;
+        let split = bx
+            .iter()
+            .position(|b| {
+                acc += b.count;
+                acc >= half
+            })
+            .unwrap_or(0)
+            .clamp(0, bx.len() - 2);
+        let upper = bx.split_off(split + 1);
+        boxes.push(bx);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+fn distance(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+fn nearest(palette: &[[f32; 3]], color: [f32; 3]) -> usize {
+    palette
+        .iter()
+        .map(|p| distance(*p, color))
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or(0, |(i, _)| i)
+}
+
+fn kmeans(bins: &[Bin], mut centers: Vec<[f32; 3]>) -> Vec<[f32; 3]> {
+    for _ in 0..KMEANS_ITERATIONS {
+        let assignment: Vec<usize> = bins
+            .par_iter()
+            .map(|b| nearest(&centers, b.color))
+            .collect();
+        let mut sums = vec![([0f32; 3], 0f32); centers.len()];
+        for (bin, i) in bins.iter().zip(&assignment) {
+            let (sum, count) = &mut sums[*i];
+            for (s, c) in sum.iter_mut().zip(bin.color) {
+                *s += c * bin.count;
+            }
+            *count += bin.count;
+        }
+        let mut moved = false;
+        for (center, (sum, count)) in centers.iter_mut().zip(sums) {
+            if count > 0. {
+                let new = sum.map(|s| s / count);
+                moved |= distance(new, *center) > 0.01;
+                *center = new;
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+    centers
+}
+
+/// Builds the palette to reduce the image to.
+pub fn palette(img: &RgbaImage, source: PaletteSource, colors: u16) -> Vec<[f32; 3]> {
+    let fixed = |colors: &[[u8; 3]]| colors.iter().map(|c| c.map(|v| v as f32)).collect();
+    match source {
+        PaletteSource::MedianCut => median_cut(histogram(img), colors.max(1) as usize),
+        PaletteSource::KMeans => {
+            let bins = histogram(img);
+            let centers = median_cut(bins.clone(), colors.max(1) as usize);
+            kmeans(&bins, centers)
+        }
+        PaletteSource::Cga => fixed(CGA),
+        PaletteSource::GameBoy => fixed(GAME_BOY),
+        PaletteSource::WebSafe => (0..216)
+            .map(|i| [i / 36, i / 6 % 6, i % 6].map(|v| v as f32 * 51.))
+            .collect(),
+    }
+}
+
+/// Threshold matrix of ordered dithering, with values between -0.5 and 0.5.
+fn bayer_matrix(size: usize) -> Vec<f32> {
+    let mut matrix = vec![0usize];
+    let mut n = 1;
+    while n < size {
+        let mut next = vec![0; n * n * 4];
+        for y in 0..n * 2 {
+            for x in 0..n * 2 {
+                let offset = [0, 2, 3, 1][(y / n) * 2 + x / n];
+                next[y * n * 2 + x] = matrix[(y % n) * n + x % n] * 4 + offset;
+            }
+        }
+        matrix = next;
+        n *= 2;
+    }
+    let cells = (size * size) as f32;
+    matrix
+        .into_iter()
+        .map(|v| (v as f32 + 0.5) / cells - 0.5)
+        .collect()
+}
+
+/// Replaces every colour of the image with one of the palette, leaving alpha untouched.
+/// The palette is in 8-bit units, but the image is dithered at its full precision.
+pub fn apply(
+    img: &mut Rgba32FImage,
+    palette: &[[f32; 3]],
+    dithering: Dithering,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let width = img.width() as usize;
+    if width == 0 {
+        return Ok(());
+    }
+    let rgb = |px: &[f32]| [px[0], px[1], px[2]].map(|v| v * 255.);
+    let write = |px: &mut [f32], color: [f32; 3]| {
+        for (c, v) in px.iter_mut().zip(color) {
+            *c = v.clamp(0., 255.) / 255.;
+        }
+    };
+
+    if let Some(kernel) = dithering.diffusion() {
+        let mut values: Vec<[f32; 3]> = img.pixels().map(|px| rgb(&px.0)).collect();
+        let height = values.len() / width;
+        for (y, row) in img.chunks_exact_mut(width * 4).enumerate() {
+            cancel.check()?;
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let old = values[y * width + x].map(|v| v.clamp(0., 255.));
+                let new = palette[nearest(palette, old)];
+                write(px, new);
+                for (dx, dy, weight) in kernel {
+                    let (nx, ny) = (x as i32 + dx, y + *dy as usize);
+                    if nx < 0 || nx as usize >= width || ny >= height {
+                        continue;
+                    }
+                    let value = &mut values[ny * width + nx as usize];
+                    for c in 0..3 {
+                        value[c] += (old[c] - new[c]) * weight;
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    let (size, matrix) = dithering
+        .bayer_size()
+        .map_or((1, vec![0.]), |size| (size, bayer_matrix(size)));
+    // Spread the thresholds over about one step between palette colours
+    let spread = 255. / (palette.len() as f32).cbrt().max(2.);
+    img.par_chunks_mut(width * 4)
+        .enumerate()
+        .try_for_each(|(y, row)| {
+            cancel.check()?;
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                let threshold = matrix[(y % size) * size + x % size] * spread;
+                let color = rgb(px).map(|v| v + threshold);
+                write(px, palette[nearest(palette, color)]);
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bin(color: [f32; 3], count: f32) -> Bin {
+        Bin { color, count }
+    }
+
+    #[test]
+    fn bayer_matrices() {
+        assert_eq!(bayer_matrix(2), [-0.375, 0.125, 0.375, -0.125]);
+        for size in [4, 8] {
+            let matrix = bayer_matrix(size);
+            // Every threshold is used once, evenly spread around 0
+            let mut ranks: Vec<usize> = matrix
+                .iter()
+                .map(|v| ((v + 0.5) * (size * size) as f32 - 0.5).round() as usize)
+                .collect();
+            ranks.sort();
+            assert_eq!(ranks, (0..size * size).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn median_cut_splits_clusters() {
+        assert_eq!(median_cut(vec![], 4), [[0.; 3]]);
+
+        let bins = vec![
+            bin([0., 0., 0.], 1.),
+            bin([10., 0., 0.], 1.),
+            bin([200., 250., 0.], 1.),
+            bin([200., 254., 0.], 1.),
+        ];
+        assert_eq!(median_cut(bins.clone(), 1), [[102.5, 126., 0.]]);
+        let mut palette = median_cut(bins.clone(), 2);
+        palette.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        assert_eq!(palette, [[5., 0., 0.], [200., 252., 0.]]);
+        // Never more colours than there are bins
+        assert_eq!(median_cut(bins, 16).len(), 4);
+    }
+}
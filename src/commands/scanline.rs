@@ -0,0 +1,173 @@
+use std::f32::consts::TAU;
+
+use eframe::egui::{Slider, Ui, Widget};
+use image::Rgba32FImage;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::{CancelToken, FilterError};
+
+/// Settings of the `ScanlineGlitch` filter. Every effect is turned off by a zero value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScanlineSettings {
+    /// Number of horizontal bands moved sideways
+    pub bands: u32,
+    /// Largest displacement of a band, as a factor of the image width
+    pub displacement: f32,
+    /// How much every other row is darkened
+    pub scanlines: f32,
+    /// Amplitude of the sinusoidal wobble of the rows, in pixels
+    pub wobble: f32,
+    /// Distance the colour smears to the right, in pixels
+    pub bleed: u32,
+    /// Number of bars of tracking noise
+    pub tracking_bars: u32,
+}
+
+impl ScanlineSettings {
+    pub fn ui(&mut self, ui: &mut Ui) {
+        Slider::new(&mut self.bands, 0..=50).text("Bands").ui(ui);
+        Slider::new(&mut self.displacement, 0.0..=0.5)
+            .text("Band displacement")
+            .ui(ui);
+        Slider::new(&mut self.scanlines, 0.0..=1.0)
+            .text("Scanlines")
+            .ui(ui);
+        Slider::new(&mut self.wobble, 0.0..=20.0)
+            .text("Tape wobble")
+            .suffix("px")
+            .ui(ui);
+        Slider::new(&mut self.bleed, 0..=32)
+            .text("Colour bleed")
+            .suffix("px")
+            .ui(ui);
+        Slider::new(&mut self.tracking_bars, 0..=10)
+            .text("Tracking bars")
+            .ui(ui);
+    }
+}
+
+/// Rows from `start` to `end`, excluded.
+struct Band {
+    start: u32,
+    end: u32,
+    offset: f32,
+}
+
+fn random_band(random: &mut ChaCha20Rng, height: u32, max_height: u32) -> (u32, u32) {
+    let start = random.random_range(0..height);
+    let size = random.random_range(1..=max_height.max(1));
+    (start, (start + size).min(height))
+}
+
+/// Linear interpolation of channel `c` of a row at the fractional position `x`, wrapping
+/// around the edges.
+fn sample(row: &[f32], width: usize, x: f32, c: usize) -> f32 {
+    let x0 = x.floor();
+    let t = x - x0;
+    let i = (x0 as i64).rem_euclid(width as i64) as usize;
+    let j = (i + 1) % width;
+    row[i * 4 + c] * (1. - t) + row[j * 4 + c] * t
+}
+
+/// Simulates a damaged analog video: displaced bands, wobbling rows, smeared colour,
+/// tracking noise and visible scanlines.
+pub fn glitch(
+    img: &mut Rgba32FImage,
+    settings: ScanlineSettings,
+    random: &mut ChaCha20Rng,
+    process_alpha: bool,
+    cancel: &CancelToken,
+) -> Result<(), FilterError> {
+    let (width, height) = img.dimensions();
+    if width == 0 || height == 0 {
+        return Ok(());
+    }
+    let max_offset = settings.displacement * width as f32;
+    let bands: Vec<Band> = (0..settings.bands)
+        .map(|_| {
+            let (start, end) = random_band(random, height, height / 10);
+            let offset = random.random_range(-1.0..=1.0) * max_offset;
+            Band { start, end, offset }
+        })
+        .collect();
+    let bars: Vec<(u32, u32)> = (0..settings.tracking_bars)
+        .map(|_| random_band(random, height, height / 20))
+        .collect();
+    let period = height as f32 / random.random_range(2.0..6.0);
+    let phase = random.random_range(0.0..TAU);
+    let noise_seed: u64 = random.random();
+
+    let width = width as usize;
+    let channels = if process_alpha { 4 } else { 3 };
+    let bleed = 1. / (settings.bleed as f32 + 1.);
+    let source = img.clone();
+    img.par_chunks_mut(width * 4)
+        .zip(source.par_chunks(width * 4))
+        .enumerate()
+        .try_for_each(|(y, (row, source))| {
+            cancel.check()?;
+            let y = y as u32;
+            // Every row has its own stream, so the rows can be done in any order
+            let mut noise = ChaCha20Rng::seed_from_u64(noise_seed);
+            noise.set_stream(y as u64);
+            let in_bar = bars.iter().any(|&(start, end)| (start..end).contains(&y));
+
+            let mut shift = settings.wobble * (TAU * y as f32 / period + phase).sin();
+            shift += bands
+                .iter()
+                .filter(|b| (b.start..b.end).contains(&y))
+                .map(|b| b.offset)
+                .sum::<f32>();
+            if in_bar {
+                shift += noise.random_range(-1.0..=1.0) * width as f32 * 0.02;
+            }
+            for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                for (c, v) in px[..channels].iter_mut().enumerate() {
+                    *v = sample(source, width, x as f32 - shift, c);
+                }
+            }
+
+            if settings.bleed > 0 {
+                // Chroma lags behind luma, as with the low bandwidth of tape
+                let (mut cb, mut cr) = (0., 0.);
+                for (x, px) in row.chunks_exact_mut(4).enumerate() {
+                    let [r, g, b] = [px[0], px[1], px[2]];
+                    let luma = 0.299 * r + 0.587 * g + 0.114 * b;
+                    let (pb, pr) = (b - luma, r - luma);
+                    if x == 0 {
+                        (cb, cr) = (pb, pr);
+                    }
+                    cb += (pb - cb) * bleed;
+                    cr += (pr - cr) * bleed;
+                    px[0] = luma + cr;
+                    px[2] = luma + cb;
+                    px[1] = (luma - 0.299 * px[0] - 0.114 * px[2]) / 0.587;
+                    for v in &mut px[..3] {
+                        *v = v.clamp(0., 1.);
+                    }
+                }
+            }
+
+            if in_bar {
+                for px in row.chunks_exact_mut(4) {
+                    let gray: f32 = noise.random_range(0.0..=1.0);
+                    let mix = noise.random_range(0.3..=0.8);
+                    for v in &mut px[..3] {
+                        *v = *v * (1. - mix) + gray * mix;
+                    }
+                }
+            }
+
+            if y % 2 == 1 {
+                for px in row.chunks_exact_mut(4) {
+                    for v in &mut px[..3] {
+                        *v *= 1. - settings.scanlines;
+                    }
+                }
+            }
+            Ok::<_, FilterError>(())
+        })
+}
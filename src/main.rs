@@ -5,10 +5,18 @@ use eframe::{
 };
 
 mod app;
+mod batch;
+mod cache;
+mod cli;
 mod commands;
+mod viewer;
 mod worker;
 
 fn main() -> eframe::Result {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
     let native_options = NativeOptions {
         viewport: ViewportBuilder {
             inner_size: Some(vec2(1280., 720.)),
@@ -0,0 +1,214 @@
+use eframe::egui::{
+    Align2, Color32, CursorIcon, PointerButton, Rect, Sense, Stroke, TextStyle, Ui, Vec2,
+    load::SizedTexture, pos2, vec2,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CompareMode {
+    Off,
+    Split,
+    SideBySide,
+    Toggle,
+}
+
+/// Displays the current render, optionally compared with the original image.
+pub struct ImageViewer {
+    mode: CompareMode,
+    split: f32,
+    /// Screen points per image pixel, `None` to fit the image in the view
+    zoom: Option<f32>,
+    /// Offset of the image center from the view center
+    pan: Vec2,
+}
+
+const FULL_UV: Rect = Rect::from_min_max(pos2(0., 0.), pos2(1., 1.));
+const MIN_ZOOM: f32 = 0.02;
+const MAX_ZOOM: f32 = 64.;
+
+/// Scale fitting an image of `size` in `area`, without upscaling.
+fn fit_scale(size: Vec2, area: Rect) -> f32 {
+    (area.width() / size.x).min(area.height() / size.y).min(1.)
+}
+
+fn caption(ui: &Ui, rect: Rect, anchor: Align2, text: &str) {
+    let painter = ui.painter_at(rect);
+    let font = TextStyle::Small.resolve(ui.style());
+    let galley = painter.layout_no_wrap(text.into(), font, Color32::WHITE);
+    let text_rect = anchor.anchor_size(anchor.pos_in_rect(&rect.shrink(4.)), galley.size());
+    painter.rect_filled(text_rect.expand(2.), 2., Color32::from_black_alpha(160));
+    painter.galley(text_rect.min, galley, Color32::WHITE);
+}
+
+impl ImageViewer {
+    pub fn new() -> Self {
+        Self {
+            mode: CompareMode::Off,
+            split: 0.5,
+            zoom: None,
+            pan: Vec2::ZERO,
+        }
+    }
+
+    /// Goes back to fitting the image in the view.
+    pub fn reset_view(&mut self) {
+        self.zoom = None;
+        self.pan = Vec2::ZERO;
+    }
+
+    fn scale(&self, size: Vec2, view: Rect) -> f32 {
+        self.zoom.unwrap_or_else(|| fit_scale(size, view))
+    }
+
+    fn image_rect(&self, size: Vec2, view: Rect) -> Rect {
+        self.scaled_rect(size, view, self.scale(size, view))
+    }
+
+    /// Rectangle of an image of `size` drawn at `scale`, centered on the panned view center.
+    fn scaled_rect(&self, size: Vec2, view: Rect, scale: f32) -> Rect {
+        let pan = if self.zoom.is_some() {
+            self.pan
+        } else {
+            Vec2::ZERO
+        };
+        Rect::from_center_size(view.center() + pan, size * scale)
+    }
+
+    fn show_toolbar(&mut self, ui: &mut Ui, can_compare: bool) {
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(can_compare, |ui| {
+                ui.label("Compare");
+                ui.selectable_value(&mut self.mode, CompareMode::Off, "Off");
+                ui.selectable_value(&mut self.mode, CompareMode::Split, "Split");
+                ui.selectable_value(&mut self.mode, CompareMode::SideBySide, "Side by side");
+                ui.selectable_value(&mut self.mode, CompareMode::Toggle, "Hold to compare")
+                    .on_hover_text("Hold the mouse button on the image to show the original");
+            });
+            ui.separator();
+            if ui.selectable_label(self.zoom.is_none(), "Fit").clicked() {
+                self.reset_view();
+            }
+            // 1:1 maps image pixels to physical pixels
+            let one_to_one = 1. / ui.ctx().pixels_per_point();
+            if ui
+                .selectable_label(self.zoom == Some(one_to_one), "1:1")
+                .clicked()
+            {
+                self.zoom = Some(one_to_one);
+                self.pan = Vec2::ZERO;
+            }
+        });
+    }
+
+    pub fn show(&mut self, ui: &mut Ui, render: SizedTexture, original: Option<SizedTexture>) {
+        self.show_toolbar(ui, original.is_some());
+        let mode = original.map_or(CompareMode::Off, |_| self.mode);
+        let (area, response) = ui.allocate_exact_size(ui.available_size(), Sense::click_and_drag());
+        let views = match (mode, original) {
+            (CompareMode::SideBySide, Some(original)) => {
+                let gap = ui.spacing().item_spacing.x;
+                let half = vec2((area.width() - gap) / 2., area.height());
+                vec![
+                    (Rect::from_min_size(area.min, half), original, "Original"),
+                    (
+                        Rect::from_min_size(area.min + vec2(half.x + gap, 0.), half),
+                        render,
+                        "Render",
+                    ),
+                ]
+            }
+            _ => vec![(area, render, "Render")],
+        };
+        let render_view = views[views.len() - 1].0;
+        let split_x = area.left() + area.width() * self.split;
+        let handle = (mode == CompareMode::Split).then(|| {
+            let rect =
+                Rect::from_center_size(pos2(split_x, area.center().y), vec2(12., area.height()));
+            ui.interact(rect, response.id.with("split"), Sense::drag())
+                .on_hover_cursor(CursorIcon::ResizeHorizontal)
+        });
+
+        // Zoom around the cursor with the mouse wheel
+        let scroll = ui.input(|i| i.smooth_scroll_delta.y);
+        if let Some(pointer) = response.hover_pos()
+            && scroll != 0.
+        {
+            let view = views
+                .iter()
+                .map(|(view, ..)| *view)
+                .find(|view| view.contains(pointer))
+                .unwrap_or(render_view);
+            let scale = self.scale(render.size, view);
+            let new_scale = (scale * (scroll / 200.).exp()).clamp(MIN_ZOOM, MAX_ZOOM);
+            let pan = if self.zoom.is_some() {
+                self.pan
+            } else {
+                Vec2::ZERO
+            };
+            let center = view.center() + pan;
+            let new_center = pointer + (center - pointer) * (new_scale / scale);
+            self.pan = new_center - view.center();
+            self.zoom = Some(new_scale);
+        }
+        if let Some(handle) = &handle
+            && let Some(pos) = handle.interact_pointer_pos()
+        {
+            self.split = ((pos.x - area.left()) / area.width()).clamp(0., 1.);
+        } else if response.dragged_by(PointerButton::Primary)
+            || response.dragged_by(PointerButton::Middle)
+        {
+            if self.zoom.is_none() {
+                self.zoom = Some(self.scale(render.size, render_view));
+                self.pan = Vec2::ZERO;
+            }
+            self.pan += response.drag_delta();
+        }
+
+        match (mode, original) {
+            (CompareMode::Split, Some(original)) => {
+                // Both images share the scale of the render, keeping their own size
+                let scale = self.scale(render.size, area);
+                let split_x = area.left() + area.width() * self.split;
+                let left = Rect::from_min_max(area.min, pos2(split_x, area.max.y));
+                ui.painter_at(area).image(
+                    render.id,
+                    self.scaled_rect(render.size, area, scale),
+                    FULL_UV,
+                    Color32::WHITE,
+                );
+                ui.painter_at(left).image(
+                    original.id,
+                    self.scaled_rect(original.size, area, scale),
+                    FULL_UV,
+                    Color32::WHITE,
+                );
+                ui.painter_at(area).vline(
+                    split_x,
+                    area.y_range(),
+                    Stroke::new(2., ui.visuals().strong_text_color()),
+                );
+                caption(ui, area, Align2::LEFT_TOP, "Original");
+                caption(ui, area, Align2::RIGHT_TOP, "Render");
+            }
+            (CompareMode::Toggle, Some(original)) if response.is_pointer_button_down_on() => {
+                let scale = self.scale(render.size, area);
+                let rect = self.scaled_rect(original.size, area, scale);
+                ui.painter_at(area)
+                    .image(original.id, rect, FULL_UV, Color32::WHITE);
+                caption(ui, area, Align2::LEFT_TOP, "Original");
+            }
+            _ => {
+                for (view, tex, label) in &views {
+                    let rect = self.image_rect(tex.size, *view);
+                    ui.painter_at(*view)
+                        .image(tex.id, rect, FULL_UV, Color32::WHITE);
+                    if views.len() > 1 {
+                        caption(ui, *view, Align2::LEFT_TOP, label);
+                    }
+                }
+            }
+        }
+        let one_to_one = 1. / ui.ctx().pixels_per_point();
+        let zoom = self.scale(render.size, render_view) / one_to_one * 100.;
+        caption(ui, area, Align2::RIGHT_BOTTOM, &format!("{zoom:.0}%"));
+    }
+}
@@ -1,12 +1,25 @@
 use std::{
+    fmt,
     path::PathBuf,
-    sync::mpsc::{Receiver, Sender, TryRecvError, channel},
+    sync::{
+        Arc,
+        mpsc::{Receiver, Sender, TryRecvError, channel},
+    },
     thread::{JoinHandle, spawn},
 };
 
 use image::{DynamicImage, ImageError};
 
-use crate::commands::CommandQueue;
+use crate::{
+    batch::{self, BatchFailure},
+    cache::RenderCache,
+    commands::{CancelToken, CommandQueue, FilterError, QueueError},
+};
+
+/// Largest width or height of the image used for live previews.
+const PREVIEW_SIZE: u32 = 1024;
+/// Largest width or height of step thumbnails.
+const THUMBNAIL_SIZE: u32 = 64;
 
 struct Worker {
     handle: JoinHandle<()>,
@@ -28,38 +41,220 @@ impl Drop for ImageWorker {
     }
 }
 
+#[derive(Debug)]
+pub enum WorkerError {
+    Image(ImageError),
+    Queue(QueueError),
+    /// The output path of a batch input is one of the inputs
+    OverwritesInput(PathBuf),
+    /// Several batch inputs would be saved to the same path
+    DuplicateOutput(PathBuf),
+}
+
+impl fmt::Display for WorkerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Image(e) => write!(f, "{e}"),
+            Self::Queue(e) => write!(f, "{e}"),
+            Self::OverwritesInput(path) => {
+                write!(f, "output {} would overwrite an input", path.display())
+            }
+            Self::DuplicateOutput(path) => {
+                write!(f, "output {} is shared with another input", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for WorkerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Image(e) => Some(e),
+            Self::Queue(e) => Some(e),
+            Self::OverwritesInput(_) | Self::DuplicateOutput(_) => None,
+        }
+    }
+}
+
+impl From<ImageError> for WorkerError {
+    fn from(e: ImageError) -> Self {
+        Self::Image(e)
+    }
+}
+
+impl From<QueueError> for WorkerError {
+    fn from(e: QueueError) -> Self {
+        Self::Queue(e)
+    }
+}
+
 pub enum WorkerResult {
     Progress(usize),
+    /// Thumbnail of the result of a single step, sent during renders that keep them
+    Step {
+        step: usize,
+        thumbnail: DynamicImage,
+    },
+    /// Full result of a step of the last render, `None` if it isn't kept anymore
+    StepImage {
+        step: usize,
+        img: Option<DynamicImage>,
+    },
     Finished(DynamicImage),
-    Error(ImageError),
+    PreviewFinished {
+        img: DynamicImage,
+        /// Size of the downscaled image the preview was rendered from
+        proxy_size: (u32, u32),
+    },
+    PreviewError(WorkerError),
+    Cancelled,
+    Error(WorkerError),
+    BatchProgress {
+        done: usize,
+        total: usize,
+        failure: Option<BatchFailure>,
+    },
+    /// The batch was cancelled before all images were processed
+    BatchCancelled,
 }
 
 enum WorkCommand {
     LoadImage(PathBuf),
     Render {
         queue: CommandQueue,
-        img: DynamicImage,
+        img: Arc<DynamicImage>,
+        generation: u64,
+        cancel: CancelToken,
+        keep_steps: bool,
+    },
+    Preview {
+        queue: CommandQueue,
+        img: Arc<DynamicImage>,
+        generation: u64,
+        cancel: CancelToken,
+    },
+    FetchStep {
+        step: usize,
+        hash: u64,
+    },
+    Batch {
+        queue: CommandQueue,
+        inputs: Vec<PathBuf>,
+        output_dir: PathBuf,
+        template: String,
+        cancel: CancelToken,
     },
 }
 
 fn image_worker(sender: Sender<WorkerResult>, receiver: Receiver<WorkCommand>) {
+    let mut cache = RenderCache::default();
+    let mut preview_cache = RenderCache::with_proxy_size(PREVIEW_SIZE);
+    // Step results of the last render that the cache doesn't keep, by queue prefix hash
+    let mut step_images: Vec<(u64, DynamicImage)> = vec![];
     loop {
         match receiver.recv() {
             Ok(c) => match c {
                 WorkCommand::LoadImage(path) => {
                     let res = match image::open(path) {
                         Ok(img) => WorkerResult::Finished(img),
-                        Err(e) => WorkerResult::Error(e),
+                        Err(e) => WorkerResult::Error(e.into()),
+                    };
+                    sender.send(res).unwrap();
+                }
+                WorkCommand::Render {
+                    queue,
+                    img,
+                    generation,
+                    cancel,
+                    keep_steps,
+                } => {
+                    step_images.clear();
+                    let prefix_hashes = queue.prefix_hashes();
+                    let cached = queue.result_hashes();
+                    let res = cache.render(queue, img, generation, &cancel, |i, img| {
+                        if keep_steps && let Some(img) = img {
+                            if cached[i - 1].is_none() {
+                                step_images.push((prefix_hashes[i - 1], img.clone()));
+                            }
+                            let thumbnail = img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+                            sender
+                                .send(WorkerResult::Step { step: i, thumbnail })
+                                .unwrap();
+                        }
+                        sender.send(WorkerResult::Progress(i)).unwrap();
+                    });
+                    let res = match res {
+                        Ok(img) => WorkerResult::Finished(img),
+                        Err(e) if matches!(e.error, FilterError::Cancelled) => {
+                            WorkerResult::Cancelled
+                        }
+                        Err(e) => WorkerResult::Error(e.into()),
                     };
                     sender.send(res).unwrap();
                 }
-                WorkCommand::Render { queue, img } => {
-                    let mut img = img;
-                    for (i, command) in queue.into_iter().enumerate() {
-                        img = command.execute(img);
-                        sender.send(WorkerResult::Progress(i + 1)).unwrap();
+                WorkCommand::Preview {
+                    queue,
+                    img,
+                    generation,
+                    cancel,
+                } => {
+                    let res = match preview_cache.render(queue, img, generation, &cancel, |_, _| {})
+                    {
+                        Ok(img) => WorkerResult::PreviewFinished {
+                            img,
+                            proxy_size: preview_cache
+                                .source_dimensions()
+                                .expect("base image should be set"),
+                        },
+                        Err(e) if matches!(e.error, FilterError::Cancelled) => continue,
+                        Err(e) => WorkerResult::PreviewError(e.into()),
+                    };
+                    sender.send(res).unwrap();
+                }
+                WorkCommand::FetchStep { step, hash } => {
+                    let img = cache
+                        .get(hash)
+                        .or_else(|| {
+                            step_images
+                                .iter()
+                                .find(|(h, _)| *h == hash)
+                                .map(|(_, img)| img)
+                        })
+                        .cloned();
+                    sender.send(WorkerResult::StepImage { step, img }).unwrap();
+                }
+                WorkCommand::Batch {
+                    queue,
+                    inputs,
+                    output_dir,
+                    template,
+                    cancel,
+                } => {
+                    let total = inputs.len();
+                    let plan = batch::plan_outputs(inputs, &output_dir, &template);
+                    for (i, (input, output)) in plan.into_iter().enumerate() {
+                        if cancel.is_cancelled() {
+                            sender.send(WorkerResult::BatchCancelled).unwrap();
+                            break;
+                        }
+                        let res = output.and_then(|output| {
+                            batch::process_file(&queue, &input, &output, &cancel)
+                        });
+                        if let Err(WorkerError::Queue(e)) = &res
+                            && matches!(e.error, FilterError::Cancelled)
+                        {
+                            sender.send(WorkerResult::BatchCancelled).unwrap();
+                            break;
+                        }
+                        let failure = res.err().map(|error| BatchFailure { path: input, error });
+                        sender
+                            .send(WorkerResult::BatchProgress {
+                                done: i + 1,
+                                total,
+                                failure,
+                            })
+                            .unwrap();
                     }
-                    sender.send(WorkerResult::Finished(img)).unwrap();
                 }
             },
             Err(_) => {
@@ -98,11 +293,80 @@ impl ImageWorker {
             .expect("worker thread unexpectedly down!!");
     }
 
-    pub fn request_render(&self, queue: CommandQueue, img: DynamicImage) {
+    /// Returns a token that can be used to cancel the requested render.
+    /// With `keep_steps`, the result of every step is sent back along with a thumbnail.
+    /// `generation` must change whenever a different base image is loaded.
+    pub fn request_render(
+        &self,
+        queue: CommandQueue,
+        img: Arc<DynamicImage>,
+        generation: u64,
+        keep_steps: bool,
+    ) -> CancelToken {
+        let cancel = CancelToken::default();
+        self.worker()
+            .sender
+            .send(WorkCommand::Render {
+                queue,
+                img,
+                generation,
+                cancel: cancel.clone(),
+                keep_steps,
+            })
+            .expect("worker thread unexpectedly down!!");
+        cancel
+    }
+
+    /// Renders the queue on a downscaled copy of the image.
+    /// Cancelled previews do not send any result back.
+    pub fn request_preview(
+        &self,
+        queue: CommandQueue,
+        img: Arc<DynamicImage>,
+        generation: u64,
+    ) -> CancelToken {
+        let cancel = CancelToken::default();
+        self.worker()
+            .sender
+            .send(WorkCommand::Preview {
+                queue,
+                img,
+                generation,
+                cancel: cancel.clone(),
+            })
+            .expect("worker thread unexpectedly down!!");
+        cancel
+    }
+
+    /// Asks for the full result of a step of the last render, `hash` being the prefix hash of
+    /// the step. The answer is a [`WorkerResult::StepImage`].
+    pub fn request_step(&self, step: usize, hash: u64) {
+        self.worker()
+            .sender
+            .send(WorkCommand::FetchStep { step, hash })
+            .expect("worker thread unexpectedly down!!");
+    }
+
+    /// Returns a token that can be used to stop the batch before its next image.
+    pub fn request_batch(
+        &self,
+        queue: CommandQueue,
+        inputs: Vec<PathBuf>,
+        output_dir: PathBuf,
+        template: String,
+    ) -> CancelToken {
+        let cancel = CancelToken::default();
         self.worker()
             .sender
-            .send(WorkCommand::Render { queue, img })
+            .send(WorkCommand::Batch {
+                queue,
+                inputs,
+                output_dir,
+                template,
+                cancel: cancel.clone(),
+            })
             .expect("worker thread unexpectedly down!!");
+        cancel
     }
 
     pub fn try_recv(&self) -> Option<WorkerResult> {